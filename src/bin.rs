@@ -1,11 +1,16 @@
-use clap::{arg, command, value_parser, Arg, ArgAction};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use clap::{arg, command, value_parser, Arg, ArgAction, ArgMatches, Command};
 
 use rest_api as lib;
 use lib::enable_logging;
-use lib::config_parser::read_config;
+use lib::config_parser::{read_config, config_from_schemas};
+use lib::database::introspect::schemas_from_database;
 use lib::database::interfaces::{SQLite3Interface, DatabaseInterface};
 use lib::app::App;
-use lib::api_http_server::routing::{BasicRoute, Route};
+use lib::api_http_server::routing::{BasicRoute, ParamRoute, Route, nested_routes};
 use lib::api_http_server::http::run_app_server;
 
 #[tokio::main]
@@ -24,8 +29,29 @@ async fn main() {
                 .long("resetdb")
                 .action(ArgAction::SetTrue)
         )
+        .arg(
+            Arg::new("migrate")
+                .short('m')
+                .long("migrate")
+                .help("Rebuilds tables whose columns have changed types or been removed from the config")
+                .action(ArgAction::SetTrue)
+        )
+        .subcommand(
+            Command::new("introspect")
+                .about("Writes a config for the tables of an existing database")
+                .arg(arg!(<DATABASE> "Path to the sqlite3 database").value_parser(value_parser!(String)))
+                .arg(
+                    arg!(-o --output <FILE> "Writes the config to a file, instead of printing it")
+                        .value_parser(value_parser!(String))
+                )
+        )
         .get_matches();
 
+    if let Some(("introspect", matches)) = cli_matches.subcommand() {
+        introspect(matches);
+        return
+    }
+
     let optional_path: Option<String> = cli_matches.get_one::<String>("config").cloned();
 
     // Read generic settings and table schemas
@@ -38,17 +64,21 @@ async fn main() {
         SQLite3Interface::delete_db(&config)
     }
 
-    let (interface, existing) = SQLite3Interface::connect(&config);
+    let (interface, _) = SQLite3Interface::connect(&config);
 
-    // assumes if existing, config tables match the database tables' structure
-    // if not existing, recreate tables from config schemas
-    if !existing {
-        interface.create_tables_from_schemas(tables.values().into_iter().collect())
-    }
+    // tables and columns missing from the database are created
+    // changed and removed columns are only applied with --migrate, as data can be lost
+    interface.migrate(tables.values().collect(), cli_matches.get_flag("migrate"));
+    interface.indexes_from_schemas(tables.values().collect());
 
     // construct route to table schema mappings
-    let mut routes = Vec::new();
+    // each table is accessed at its route, and single records at route/{id}
+    // records related to a record are accessed at route/{field}/relation, e.g. /companies/{company_id}/people
+    let mut routes = nested_routes(&tables);
     for table in tables {
+        routes.push(Box::new(
+            ParamRoute::new(format!("{}/{{id}}", table.0.trim_end_matches('/')), table.1.clone())
+        ) as Box<dyn Route + Send + Sync>);
         routes.push(Box::new(
             BasicRoute {route: table.0, table_schema: table.1}
         ) as Box<dyn Route + Send + Sync>)
@@ -62,3 +92,31 @@ async fn main() {
 
     run_app_server(config.get("host").expect("No host in config").parse().expect("Can't parse IP"), app).await;
 }
+
+// writes a config for an existing database, which can be used to serve it
+fn introspect(matches: &ArgMatches) {
+    let db_path = matches.get_one::<String>("DATABASE").expect("No database path");
+    let output = matches.get_one::<String>("output");
+
+    // tables that can't be served are logged, without mixing with a printed config
+    enable_logging(&HashMap::from([("loglevel".to_string(), "warn".to_string())]));
+
+    // opening a database that doesn't exist would create it
+    if !Path::new(db_path).exists() {
+        panic!("Can't find database at: {}", db_path)
+    }
+    let connection = sqlite3::open(db_path).unwrap_or_else(|_| panic!("Can't open sqlite3 database at: {}", db_path));
+    let tables = schemas_from_database(&connection)
+        .unwrap_or_else(|e| panic!("Can't read tables of database: {:?}", e.message));
+
+    let config = format!(
+        "database=\"sqlite3\"\ndatabase_path={}\nloglevel=\"info\"\nhost=\"127.0.0.1:3000\"\n\n{}",
+        toml::Value::String(db_path.clone()),
+        config_from_schemas(&tables)
+    );
+
+    match output {
+        Some(output) => fs::write(output, config).unwrap_or_else(|e| panic!("Can't write config to {}: {}", output, e)),
+        None => print!("{}", config)
+    }
+}
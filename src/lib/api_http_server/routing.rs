@@ -1,8 +1,11 @@
-use super::super::database::table_schema::SqlTableSchema;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use super::super::database::table_schema::{SqlTableSchema, RelationKind};
 
 
 /// Example
-/// ```
+/// ```ignore
 /// let routes = routes!(
 ///    ("/people", PeopleTableSchema),
 ///    ("/jobs", JobsTableSchema)
@@ -17,10 +20,39 @@ macro_rules! routes {
     }};
 }
 
+// values captured from a route's {param} segments, stored in the request extensions
+#[derive(Clone, Debug, Default)]
+pub struct PathParams(pub HashMap<String, String>);
+
 pub trait Route {
     // returns matching table name for route
     fn matches_uri(&self, uri: String) -> bool;
     fn get_schema(&self) -> &SqlTableSchema;
+
+    // values of any parameters in the route, only called if matches_uri is true
+    fn path_params(&self, _uri: String) -> PathParams {
+        PathParams::default()
+    }
+
+    // whether each segment of the route is literal, rather than a parameter
+    // used to choose between routes matching the same uri
+    fn literal_segments(&self) -> Vec<bool> {
+        Vec::new()
+    }
+}
+
+/// The route used for a uri, if any route matches it
+/// Of several matching routes, the route whose first different segment is literal is used, whatever order the routes are in
+/// ```ignore
+/// // /people/archive is used for /people/archive, and /people/{id} for /people/5
+/// let route = match_route(&routes, "/people/archive");
+/// ```
+pub fn match_route<'a>(routes: &'a [Box<dyn Route + Send + Sync>], uri: &str) -> Option<&'a (dyn Route + Send + Sync)> {
+    routes.iter()
+        .filter(|route| route.matches_uri(uri.to_string()))
+        // the first of equally ranked routes
+        .min_by_key(|route| Reverse(route.literal_segments()))
+        .map(|route| route.as_ref())
 }
 
 #[derive(Debug)]
@@ -45,27 +77,272 @@ impl Route for BasicRoute {
     fn get_schema(&self) -> &SqlTableSchema {
         &self.table_schema
     }
+    fn literal_segments(&self) -> Vec<bool> {
+        vec![true; self.route.trim_end_matches('/').split('/').count()]
+    }
+}
+
+/// A route containing `{param}` segments, which match any single segment of a uri
+/// ```ignore
+/// let route = ParamRoute::new("/people/{id}".to_string(), PeopleTableSchema);
+/// ```
+#[derive(Debug)]
+pub struct ParamRoute {
+    pub route: String,
+    pub table_schema: SqlTableSchema,
+}
+
+impl ParamRoute {
+    pub fn new(route: String, table_schema: SqlTableSchema) -> Self {
+        Self {
+            route,
+            table_schema
+        }
+    }
+
+    // Some(param name) if the segment is a parameter, else None
+    fn param_name(segment: &str) -> Option<&str> {
+        segment.strip_prefix('{')?.strip_suffix('}')
+    }
+}
+
+impl Route for ParamRoute {
+    fn matches_uri(&self, uri: String) -> bool {
+        let route_segments: Vec<&str> = self.route.trim_end_matches('/').split('/').collect();
+        let uri_segments: Vec<&str> = uri.trim_end_matches('/').split('/').collect();
+
+        if route_segments.len() != uri_segments.len() {
+            return false
+        }
+
+        route_segments.iter().zip(uri_segments.iter()).all(|(route_segment, uri_segment)| {
+            match Self::param_name(route_segment) {
+                Some(_) => !uri_segment.is_empty(),
+                None => route_segment == uri_segment
+            }
+        })
+    }
+
+    fn get_schema(&self) -> &SqlTableSchema {
+        &self.table_schema
+    }
+
+    fn path_params(&self, uri: String) -> PathParams {
+        let mut params = HashMap::new();
+
+        for (route_segment, uri_segment) in self.route.split('/').zip(uri.split('/')) {
+            if let Some(name) = Self::param_name(route_segment) {
+                params.insert(name.to_ascii_lowercase(), percent_decode(uri_segment));
+            }
+        }
+
+        PathParams(params)
+    }
+
+    fn literal_segments(&self) -> Vec<bool> {
+        self.route.trim_end_matches('/').split('/').map(|segment| Self::param_name(segment).is_none()).collect()
+    }
+}
+
+/// Routes to the records related to a record, for every table referenced by another table
+/// The parent's parameter is the referencing field, so it filters the related records
+/// ```ignore
+/// // for people.company_id referencing companies, with tables from read_config
+/// // /companies/{company_id}/people and /companies/{company_id}/people/{id}
+/// let routes = nested_routes(&tables);
+/// ```
+pub fn nested_routes(tables: &HashMap<String, SqlTableSchema>) -> Vec<Box<dyn Route + Send + Sync>> {
+    let mut routes = Vec::new();
+
+    for (route, table) in tables {
+        let relations = table.relations.iter().filter(|relation| relation.kind == RelationKind::OneToMany);
+        for relation in relations {
+            // related tables in relations are only nested to the expand depth, so the full schema is used
+            let related_table = tables.values().find(|other| other.name == relation.table.name);
+            if related_table.is_none() {
+                continue
+            }
+            let related_table = related_table.unwrap();
+
+            let nested_route = format!("{}/{{{}}}/{}", route.trim_end_matches('/'), relation.field, relation.name);
+            routes.push(Box::new(
+                ParamRoute::new(format!("{}/{{id}}", nested_route), related_table.clone())
+            ) as Box<dyn Route + Send + Sync>);
+            routes.push(Box::new(
+                ParamRoute::new(nested_route, related_table.clone())
+            ) as Box<dyn Route + Send + Sync>);
+        }
+    }
+
+    routes
 }
 
 pub fn split_uri_args(uri: String) -> (String, String) {
-    // split at last ?
-    let base_uri;
-    let uri_args;
-
-    let whole_uri = uri.to_string();
-    let path = whole_uri.chars().rev().position(|x| x == '?');
-
-    match path {
-        None => {
-            base_uri = whole_uri;
-            uri_args = String::new();
-        },
-        Some(v) => {
-            let (base, args) = whole_uri.split_at(whole_uri.len()-v);
-            let stripped = base.strip_suffix('?');
-            base_uri = stripped.unwrap_or(base).to_string();
-            uri_args = args.to_string();
+    // split at first ?, as the query string can contain ?
+    match uri.split_once('?') {
+        Some((base_uri, uri_args)) => (base_uri.to_string(), uri_args.to_string()),
+        None => (uri, String::new())
+    }
+}
+
+// decoded (key, value) pairs of a query string, in the order they appear
+// keys are lowercased, values keep their case
+// an arg without '=' has an empty value
+pub fn parse_uri_args(uri_args: &str) -> Vec<(String, String)> {
+    uri_args
+        .split('&')
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| {
+            let (key, value) = arg.split_once('=').unwrap_or((arg, ""));
+            // '+' is a space in query strings, and '%2B' is a '+'
+            (
+                percent_decode(&key.replace('+', " ")).to_lowercase(),
+                percent_decode(&value.replace('+', " "))
+            )
+        })
+        .collect()
+}
+
+// decodes %XX escapes, as in RFC 3986
+// invalid escapes are kept as they are, and invalid UTF-8 is replaced
+pub fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => bytes.get(i+1..i+3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()),
+            _ => None
+        };
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
         }
     }
-    (base_uri, uri_args)
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // routes named after their path, with a table of the same name
+    fn routes(paths: &[&str]) -> Vec<Box<dyn Route + Send + Sync>> {
+        paths.iter().map(|path| {
+            let table = SqlTableSchema::new(path, Vec::new());
+            match path.contains('{') {
+                true => Box::new(ParamRoute::new(path.to_string(), table)) as Box<dyn Route + Send + Sync>,
+                false => Box::new(BasicRoute::new(path.to_string(), table)) as Box<dyn Route + Send + Sync>,
+            }
+        }).collect()
+    }
+
+    // the path of the route used for the uri
+    fn matched(routes: &[Box<dyn Route + Send + Sync>], uri: &str) -> Option<String> {
+        match_route(routes, uri).map(|route| route.get_schema().name.clone())
+    }
+
+    #[test]
+    fn parameters_match_one_segment() {
+        let route = ParamRoute::new("/people/{id}".to_string(), SqlTableSchema::new("people", Vec::new()));
+
+        assert!(route.matches_uri("/people/5".to_string()));
+        assert!(route.matches_uri("/people/5/".to_string()));
+        assert!(!route.matches_uri("/people".to_string()));
+        assert!(!route.matches_uri("/people/".to_string()));
+        assert!(!route.matches_uri("/people/5/pets".to_string()));
+        assert!(!route.matches_uri("/companies/5".to_string()));
+    }
+
+    #[test]
+    fn path_params_are_decoded_with_lowercase_names() {
+        let route = ParamRoute::new("/companies/{Company_ID}/people/{id}".to_string(), SqlTableSchema::new("people", Vec::new()));
+        let PathParams(params) = route.path_params("/companies/a%20b/people/5".to_string());
+
+        assert_eq!(params, HashMap::from([
+            ("company_id".to_string(), "a b".to_string()),
+            ("id".to_string(), "5".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn literal_segments_are_matched_before_parameters() {
+        for paths in [["/people/{id}", "/people/archive"], ["/people/archive", "/people/{id}"]] {
+            let routes = routes(&paths);
+
+            assert_eq!(matched(&routes, "/people/archive").as_deref(), Some("/people/archive"));
+            assert_eq!(matched(&routes, "/people/5").as_deref(), Some("/people/{id}"));
+            assert_eq!(matched(&routes, "/people"), None);
+        }
+
+        // the first segment that is different decides
+        let routes = routes(&["/a/{x}/c", "/a/b/{y}"]);
+        assert_eq!(matched(&routes, "/a/b/c").as_deref(), Some("/a/b/{y}"));
+    }
+
+    fn args(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(percent_decode("O%27Neil"), "O'Neil");
+        assert_eq!(percent_decode("caf%C3%A9%2c%2C"), "café,,");
+    }
+
+    #[test]
+    fn keeps_invalid_escapes() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz%4g"), "%zz%4g");
+        assert_eq!(percent_decode("%%41"), "%A");
+        // escapes that aren't UTF-8 are replaced
+        assert_eq!(percent_decode("a%FFb"), "a\u{FFFD}b");
+    }
+
+    #[test]
+    fn plus_is_a_space_and_escaped_plus_is_a_plus() {
+        assert_eq!(parse_uri_args("name=a+b&sum=1%2B1"), args(&[("name", "a b"), ("sum", "1+1")]));
+        // '+' isn't decoded in path parameters
+        assert_eq!(percent_decode("a+b"), "a+b");
+    }
+
+    #[test]
+    fn splits_at_the_first_equals() {
+        assert_eq!(parse_uri_args("filter=a=b&eq=%3D"), args(&[("filter", "a=b"), ("eq", "=")]));
+    }
+
+    #[test]
+    fn lowercases_keys_but_not_values() {
+        assert_eq!(parse_uri_args("Name=John&AGE%5BGT%5D=3"), args(&[("name", "John"), ("age[gt]", "3")]));
+    }
+
+    #[test]
+    fn keeps_empty_values_and_repeated_keys() {
+        assert_eq!(
+            parse_uri_args("name=&flag&&name=b&name=a"),
+            args(&[("name", ""), ("flag", ""), ("name", "b"), ("name", "a")])
+        );
+        assert!(parse_uri_args("").is_empty());
+    }
+
+    #[test]
+    fn splits_at_the_first_question_mark() {
+        assert_eq!(
+            split_uri_args("/people?name=who?".to_string()),
+            ("/people".to_string(), "name=who?".to_string())
+        );
+        assert_eq!(split_uri_args("/people".to_string()), ("/people".to_string(), String::new()));
+    }
 }
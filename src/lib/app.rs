@@ -1,14 +1,40 @@
 
 use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use super::database::table_schema::SqlTableSchema;
 
-use super::api_http_server::routing::{Route, split_uri_args};
+use super::api_http_server::routing::{Route, PathParams, split_uri_args, match_route};
 use super::api_http_server::middleware::Middleware;
 use super::database::interfaces::DatabaseInterface;
+use super::database::error::{QueryErr, ErrorCode};
+use super::database::query::allowed_methods;
 
-use hyper::{Body, Request, Response, StatusCode};
+use hyper::{Body, Request, Response};
+use hyper::header::HeaderValue;
+
+static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// identifies a request in logs and error responses, stored in the request extensions
+// taken from the X-Request-Id header if the client sends one
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    fn from_request(req: &Request<Body>) -> Self {
+        let header = req.headers().get("X-Request-Id").and_then(|id| id.to_str().ok());
+        if let Some(id) = header {
+            return Self(id.to_string())
+        }
+
+        // unique for this process, and unlikely to be repeated after restarting
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let count = REQUEST_COUNT.fetch_add(1, Ordering::Relaxed);
+        Self(format!("{:x}-{:x}", start, count))
+    }
+}
 
 
 pub struct App {
@@ -18,51 +44,59 @@ pub struct App {
 }
 
 impl App {
-    fn match_route(&self, uri: String) -> Option<&SqlTableSchema> {
-        for route in &self.routes {
-            let route_match = route.matches_uri(uri.clone());
-            if route_match {
-                return Some(route.get_schema())
-            }
-        }
-        None
+    fn match_route(&self, uri: String) -> Option<(&SqlTableSchema, PathParams)> {
+        match_route(&self.routes, &uri).map(|route| (route.get_schema(), route.path_params(uri)))
     }
 
     pub async fn handle_http_request(&self, req: Request<Body>, addr: SocketAddr) -> Result<Response<Body>, Infallible> {
-        if req.method() == hyper::Method::OPTIONS {
-            let response = Response::builder()
-                .header("Allow", "OPTIONS, GET, POST, DELETE, PATCH")
-                .header("Accept", "application/json")
-                .body(
-                    Body::empty()
-                );
-            return Ok(response.unwrap())
-        }
-
         let mut req = req;
 
-        log::info!("{} Request ({}) at {}", req.method(), addr, req.uri());
+        let request_id = RequestId::from_request(&req);
+        log::info!("{} Request ({}) at {} [{}]", req.method(), addr, req.uri(), request_id.0);
+        req.extensions_mut().insert(request_id.clone());
 
         let (base_uri, _) = split_uri_args(req.uri().to_string());
 
-        let table_schema = self.match_route(base_uri);
+        let table_schema = match self.match_route(base_uri) {
+            Some((table_schema, path_params)) => {
+                req.extensions_mut().insert(path_params);
+                Some(table_schema)
+            },
+            None => None
+        };
 
-        for middleware in &self.middleware {
-            middleware.process_request(&mut req);
+        // OPTIONS requests are answered without middleware
+        let options = req.method() == hyper::Method::OPTIONS;
+        if !options {
+            for middleware in &self.middleware {
+                middleware.process_request(&mut req);
+            }
         }
 
-        let response: Response<Body> = match table_schema {
+        let mut response: Response<Body> = match table_schema {
             None => {
+                QueryErr::new(ErrorCode::RouteNotFound, "Route not found".to_string())
+                    .to_response(Some(&request_id.0))
+            },
+            // methods of the matched route, as routes to a single record by id can't be used to add records
+            Some(_) if options => {
+                let single_record = req.extensions()
+                    .get::<PathParams>()
+                    .is_some_and(|PathParams(params)| params.contains_key("id"));
                 Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(
-                        Body::from("Route not found")
-                    ).unwrap()
+                    .header("Allow", allowed_methods(single_record))
+                    .header("Accept", "application/json")
+                    .body(Body::empty())
+                    .unwrap()
             },
             Some(table_schema) => {
                 self.database_interface.process_api_request(&mut req, table_schema).await
             }
         };
+
+        if let Ok(id) = HeaderValue::from_str(&request_id.0) {
+            response.headers_mut().insert("X-Request-Id", id);
+        }
         Ok(response)
     }
 }
@@ -3,12 +3,20 @@ use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 
-use super::database::table_schema::SqlTableSchema;
+use super::database::table_schema::{SqlTableSchema, FieldOptions, Index, relate_tables};
 use super::database::interfaces::SQLType;
+use super::database::value::{json_to_sql_value, type_name};
+use super::database::migration::{MIGRATIONS_TABLE, INDEXES_TABLE};
 
+use json::JsonValue;
+use sqlite3::Value as SqlValue;
 use toml::Value;
 
 const DEFAULT_CONFIG_PATH: &str = "server_config.toml";
+// keys in a table's config that aren't fields
+const TABLE_OPTIONS: [&str; 8] = [
+    "route", "page_size", "max_page_size", "max_batch_size", "unique", "indexes", "allow_bulk_delete", "allow_bulk_update"
+];
 
 pub fn read_config(optional_path: Option<&str>) -> (HashMap<String, String>, HashMap<String, SqlTableSchema>) {
     // will panic with error message if config file can't be read, as this is unrecoverable
@@ -50,35 +58,433 @@ pub fn read_config(optional_path: Option<&str>) -> (HashMap<String, String>, Has
     let mut table_routes = HashMap::new();
     for table in table_data {
         let table_name = table.0;
+        if table_name == MIGRATIONS_TABLE || table_name == INDEXES_TABLE {
+            panic!("Table name is used to record migrations: {}", table_name)
+        }
 
-        let table_attributes = table.1.as_table_mut().expect("Contents of table aren't of toml 'Table' type");
+        // options aren't removed from the table, as removing changes the order of the remaining keys
+        let table_attributes = table.1.as_table().expect("Contents of table aren't of toml 'Table' type");
 
-        let route = table_attributes.remove("route")
+        let route = table_attributes.get("route")
             .expect("Table has no 'route'");
 
         let route = route
             .as_str()
             .expect("Route value isn't 'String'");
 
-        let mut table_schema_mapping = HashMap::new();
+        let page_size = table_attributes.get("page_size")
+            .map(|value| read_positive_size("page_size", value));
+        let max_page_size = table_attributes.get("max_page_size")
+            .map(|value| read_positive_size("max_page_size", value));
+        let max_batch_size = table_attributes.get("max_batch_size")
+            .map(|value| read_positive_size("max_batch_size", value));
+        let allow_bulk_delete = table_attributes.get("allow_bulk_delete")
+            .is_some_and(|allow| allow.as_bool().expect("'allow_bulk_delete' isn't 'Boolean'"));
+        let allow_bulk_update = table_attributes.get("allow_bulk_update")
+            .is_some_and(|allow| allow.as_bool().expect("'allow_bulk_update' isn't 'Boolean'"));
+
+        // toml tables keep the order of their keys, so columns are in the declared order
+        let mut table_schema_mapping = Vec::new();
+        let mut field_options = HashMap::new();
+        // fields with 'unique = true' in their options
+        let mut unique_fields = Vec::new();
         
         for field in table_attributes {
-            let sql_type_string = field.1.as_str().expect("Encountered non-string SQL type value").to_ascii_lowercase();
+            if TABLE_OPTIONS.contains(&field.0.as_str()) {
+                continue
+            }
+            let field_name = field.0.to_ascii_lowercase();
+
+            // a field is a type, or an inline table with the type and options
+            let sql_type_value = match field.1.as_table() {
+                Some(options) => options.get("type").expect("Field has no 'type'"),
+                None => field.1
+            };
+
+            let sql_type_string = sql_type_value.as_str().expect("Encountered non-string SQL type value").to_ascii_lowercase();
             let field_sql_type = match sql_type_string.as_str() {
                 "null" => Some(SQLType::Null),
                 "real" => Some(SQLType::Real),
                 "integer" => Some(SQLType::Integer),
                 "text" => Some(SQLType::Text),
+                "boolean" => Some(SQLType::Boolean),
+                "date" => Some(SQLType::Date),
+                "datetime" => Some(SQLType::DateTime),
+                "blob" => Some(SQLType::Blob),
+                "json" => Some(SQLType::Json),
                 _ => None
             };
             if field_sql_type.is_none() {
                 panic!("Invalid SQL type found in table field: {}", sql_type_string);
             }
-            table_schema_mapping.insert(field.0.to_ascii_lowercase().clone(), field_sql_type.unwrap());
+            let field_sql_type = field_sql_type.unwrap();
+
+            if let Some(options) = field.1.as_table() {
+                field_options.insert(field_name.clone(), read_field_options(&field_name, &field_sql_type, options));
+
+                // fields can also be declared unique in their options
+                // read from the field's own options, as its key in the config may not be lowercase
+                let field_unique = options.get("unique")
+                    .is_some_and(|unique| unique.as_bool().expect("'unique' of field isn't 'Boolean'"));
+                if field_unique {
+                    unique_fields.push(field_name.clone());
+                }
+            }
+            table_schema_mapping.push((field_name, field_sql_type));
+        }
+
+        let mut unique = Vec::new();
+        if let Some(unique_fields) = table_attributes.get("unique") {
+            for field in unique_fields.as_array().expect("'unique' isn't 'Array'") {
+                let field = field.as_str().expect("Encountered non-string field in 'unique'").to_ascii_lowercase();
+                if !table_schema_mapping.iter().any(|(name, _)| *name == field) {
+                    panic!("Unique field isn't a field of the table: {}", field)
+                }
+                unique.push(field);
+            }
+        }
+        for field in unique_fields {
+            if !unique.contains(&field) {
+                unique.push(field);
+            }
+        }
+
+        let mut indexes: Vec<Index> = Vec::new();
+        if let Some(index_values) = table_attributes.get("indexes") {
+            for index in index_values.as_array().expect("'indexes' isn't 'Array'") {
+                let index = read_index(table_name, &table_schema_mapping, index);
+                if indexes.iter().any(|other| other.name == index.name) {
+                    panic!("Table '{}' has several indexes named '{}', give them a 'name'", table_name, index.name)
+                }
+                indexes.push(index);
+            }
         }
 
-        table_routes.insert(route.to_string(), SqlTableSchema {name: table_name.clone(), fields: table_schema_mapping});
+        table_routes.insert(
+            route.to_string(),
+            SqlTableSchema {
+                name: table_name.clone(),
+                fields: table_schema_mapping,
+                field_options,
+                page_size,
+                max_page_size,
+                max_batch_size,
+                unique,
+                indexes,
+                allow_bulk_delete,
+                allow_bulk_update,
+                ..SqlTableSchema::default()
+            }
+        );
+    }
+
+    // references are checked once every table is read, as tables can reference tables declared after them
+    for schema in table_routes.values() {
+        for (field, table) in schema.references() {
+            if !table_routes.values().any(|other| other.name == *table) {
+                panic!("Field '{}' references a table that doesn't exist: {}", field, table)
+            }
+            if !matches!(schema.field_type(field), Some(SQLType::Integer)) {
+                panic!("Field '{}' references a table, so must be 'integer'", field)
+            }
+        }
+    }
+
+    // relations are found once every table is read
+    let tables: Vec<SqlTableSchema> = table_routes.values().cloned().collect();
+    for related in relate_tables(&tables) {
+        if let Some(schema) = table_routes.values_mut().find(|schema| schema.name == related.name) {
+            *schema = related;
+        }
     }
 
     (general_config, table_routes)
 }
+
+// the [table.*] sections of a config for tables, the reverse of read_config
+// e.g. for tables read from an existing database by schemas_from_database
+pub fn config_from_schemas(tables: &HashMap<String, SqlTableSchema>) -> String {
+    let mut routes: Vec<(&String, &SqlTableSchema)> = tables.iter().collect();
+    routes.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+
+    let mut sections = Vec::new();
+    for (route, schema) in routes {
+        let mut lines = vec![
+            format!("[table.{}]", schema.name),
+            format!("route = {}", Value::String(route.clone())),
+        ];
+
+        if !schema.unique.is_empty() {
+            let unique = schema.unique.iter().map(|field| Value::String(field.clone())).collect();
+            lines.push(format!("unique = {}", Value::Array(unique)));
+        }
+        if !schema.indexes.is_empty() {
+            let indexes: Vec<String> = schema.indexes.iter().map(|index| index_config(&schema.name, index)).collect();
+            lines.push(format!("indexes = [{}]", indexes.join(", ")));
+        }
+
+        for (field, sql_type) in &schema.fields {
+            let mut options = vec![("type", Value::String(type_name(sql_type).to_string()))];
+            if let Some(field_options) = schema.field_options(field) {
+                if field_options.not_null {
+                    options.push(("nullable", Value::Boolean(false)));
+                }
+                if let Some(default) = field_options.default.as_ref().and_then(|default| sql_to_toml(sql_type, default)) {
+                    options.push(("default", default));
+                }
+                if let Some(check) = &field_options.check {
+                    options.push(("check", Value::String(check.clone())));
+                }
+                if let Some(collate) = &field_options.collate {
+                    options.push(("collate", Value::String(collate.to_ascii_lowercase())));
+                }
+                if let Some(references) = &field_options.references {
+                    options.push(("references", Value::String(references.clone())));
+                }
+                if let Some(on_delete) = &field_options.on_delete {
+                    options.push(("on_delete", Value::String(on_delete.to_ascii_lowercase())));
+                }
+            }
+
+            // a field with only a type is written as the type
+            match options.as_slice() {
+                [(_, sql_type)] => lines.push(format!("{} = {}", field, sql_type)),
+                _ => lines.push(format!("{} = {}", field, inline_table(&options)))
+            }
+        }
+
+        sections.push(lines.join("\n"));
+    }
+
+    sections.join("\n\n") + "\n"
+}
+
+// an index as an inline table, with its name in the config, which is between the table name and '_idx'
+fn index_config(table_name: &str, index: &Index) -> String {
+    let fields = index.fields.iter().map(|field| Value::String(field.clone())).collect();
+    let mut options = vec![("fields", Value::Array(fields))];
+    if index.unique {
+        options.push(("unique", Value::Boolean(true)));
+    }
+    if let Some(condition) = &index.condition {
+        options.push(("where", Value::String(condition.clone())));
+    }
+
+    let name = index.name
+        .strip_prefix(&format!("{}_", table_name))
+        .and_then(|name| name.strip_suffix("_idx"))
+        .unwrap_or(&index.name);
+    if name != index.fields.join("_") {
+        options.push(("name", Value::String(name.to_string())));
+    }
+
+    inline_table(&options)
+}
+
+// e.g. { type = "integer", nullable = false }
+// values are written in the order given, which toml tables don't keep when written
+fn inline_table(options: &[(&str, Value)]) -> String {
+    let options: Vec<String> = options.iter().map(|(key, value)| format!("{} = {}", toml_key(key), inline_value(value))).collect();
+    format!("{{ {} }}", options.join(", "))
+}
+
+// a value on one line, as toml writes tables in a value, e.g. of a json default, as sections
+fn inline_value(value: &Value) -> String {
+    match value {
+        Value::Table(table) => {
+            let options: Vec<(&str, Value)> = table.iter().map(|(key, value)| (key.as_str(), value.clone())).collect();
+            inline_table(&options)
+        },
+        Value::Array(items) => format!("[{}]", items.iter().map(inline_value).collect::<Vec<String>>().join(", ")),
+        _ => value.to_string()
+    }
+}
+
+// keys that aren't bare keys are quoted
+fn toml_key(key: &str) -> String {
+    match !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        true => key.to_string(),
+        false => Value::String(key.to_string()).to_string()
+    }
+}
+
+// a value of the field's type as it is written in the config, the reverse of json_to_sql_value
+fn sql_to_toml(sql_type: &SQLType, value: &SqlValue) -> Option<Value> {
+    match (sql_type, value) {
+        (SQLType::Boolean, SqlValue::Integer(i)) => Some(Value::Boolean(*i != 0)),
+        // json is stored as json text, which is the toml value of the json, rather than a string
+        (SQLType::Json, SqlValue::String(s)) => json::parse(s).ok().and_then(|json| json_to_toml(&json)),
+        (_, SqlValue::Integer(i)) => Some(Value::Integer(*i)),
+        (_, SqlValue::Float(f)) => Some(Value::Float(*f)),
+        (_, SqlValue::String(s)) => Some(Value::String(s.clone())),
+        (_, SqlValue::Binary(b)) => Some(Value::String(base64::encode(b))),
+        (_, SqlValue::Null) => None,
+    }
+}
+
+// a number of rows, such as a page size, from the table's option
+fn read_positive_size(option: &str, value: &Value) -> usize {
+    let size = value.as_integer().unwrap_or_else(|| panic!("'{}' isn't 'Integer'", option));
+    if size <= 0 {
+        panic!("'{}' must be greater than 0, found: {}", option, size)
+    }
+    size as usize
+}
+
+// an index is a field, an array of fields, or an inline table with the fields and options
+// e.g. "age", ["name", "age"] or { fields = ["age"], unique = true, where = "age >= 18", name = "adults" }
+fn read_index(table_name: &str, fields: &[(String, SQLType)], value: &Value) -> Index {
+    let (field_values, options) = match value {
+        Value::String(_) => (vec![value.clone()], None),
+        Value::Array(field_values) => (field_values.clone(), None),
+        Value::Table(options) => match options.get("fields") {
+            Some(Value::Array(field_values)) => (field_values.clone(), Some(options)),
+            Some(field) => (vec![field.clone()], Some(options)),
+            None => panic!("Index of table '{}' has no 'fields'", table_name)
+        },
+        _ => panic!("Index of table '{}' isn't a field, 'Array' or 'Table'", table_name)
+    };
+
+    let mut index_fields = Vec::new();
+    for field in field_values {
+        let field = field.as_str().expect("Encountered non-string field in index").to_ascii_lowercase();
+        // fields are used in SQL, so must be fields of the table
+        if field != "id" && !fields.iter().any(|(name, _)| *name == field) {
+            panic!("Index field isn't a field of table '{}': {}", table_name, field)
+        }
+        index_fields.push(field);
+    }
+    if index_fields.is_empty() {
+        panic!("Index of table '{}' has no fields", table_name)
+    }
+
+    let name = options.and_then(|options| options.get("name")).map(|name| {
+        let name = name.as_str().expect("Index 'name' isn't 'String'").to_ascii_lowercase();
+        // names are used in SQL, so must be a name
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            panic!("Invalid index name: {}", name)
+        }
+        name
+    });
+    let unique = options.and_then(|options| options.get("unique"))
+        .is_some_and(|unique| unique.as_bool().expect("Index 'unique' isn't 'Boolean'"));
+    // conditions are SQL from the config, which is trusted
+    let condition = options.and_then(|options| options.get("where"))
+        .map(|condition| condition.as_str().expect("Index 'where' isn't 'String'").to_string());
+
+    Index {
+        name: format!("{}_{}_idx", table_name, name.unwrap_or_else(|| index_fields.join("_"))),
+        fields: index_fields,
+        unique,
+        condition,
+    }
+}
+
+fn read_field_options(field: &str, sql_type: &SQLType, options: &toml::value::Table) -> FieldOptions {
+    let collate = options.get("collate").map(|collate| {
+        let collate = collate.as_str().expect("Collation isn't 'String'");
+        // collations are used in SQL, so must be a name
+        if collate.is_empty() || !collate.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            panic!("Invalid collation: {}", collate)
+        }
+        collate.to_ascii_uppercase()
+    });
+
+    let not_null = options.get("nullable")
+        .is_some_and(|nullable| !nullable.as_bool().expect("'nullable' isn't 'Boolean'"));
+
+    // defaults are converted in the same way as values in requests
+    let default = options.get("default").map(|default| {
+        json_to_sql_value(field, sql_type, &toml_to_json(default))
+            .unwrap_or_else(|e| panic!("Invalid default: {}", e))
+    });
+
+    let check = options.get("check")
+        .map(|check| check.as_str().expect("'check' isn't 'String'").to_string());
+
+    let references = options.get("references")
+        .map(|table| table.as_str().expect("'references' isn't 'String'").to_string());
+
+    let on_delete = options.get("on_delete").map(|action| {
+        let action = action.as_str().expect("'on_delete' isn't 'String'").to_ascii_uppercase();
+        // actions are used in SQL, so must be one of the valid actions
+        if !["CASCADE", "SET NULL", "SET DEFAULT", "RESTRICT", "NO ACTION"].contains(&action.as_str()) {
+            panic!("Invalid 'on_delete' action: {}", action)
+        }
+        action
+    });
+    if on_delete.is_some() && references.is_none() {
+        panic!("Field '{}' has 'on_delete', but doesn't reference a table", field)
+    }
+
+    FieldOptions {collate, not_null, default, check, references, on_delete}
+}
+
+fn toml_to_json(value: &Value) -> JsonValue {
+    match value {
+        Value::String(s) => s.as_str().into(),
+        Value::Integer(i) => (*i).into(),
+        Value::Float(f) => (*f).into(),
+        Value::Boolean(b) => (*b).into(),
+        Value::Datetime(datetime) => datetime.to_string().into(),
+        Value::Array(items) => JsonValue::Array(items.iter().map(toml_to_json).collect()),
+        Value::Table(table) => {
+            let mut object = JsonValue::new_object();
+            for (key, value) in table {
+                object[key.as_str()] = toml_to_json(value);
+            }
+            object
+        }
+    }
+}
+
+// None for null, which toml doesn't have
+fn json_to_toml(value: &JsonValue) -> Option<Value> {
+    match value {
+        JsonValue::Null => None,
+        JsonValue::Short(_) | JsonValue::String(_) => value.as_str().map(|s| Value::String(s.to_string())),
+        JsonValue::Number(_) => match value.as_i64() {
+            Some(i) if value.as_f64().is_some_and(|f| f.fract() == 0.0) => Some(Value::Integer(i)),
+            _ => value.as_f64().map(Value::Float)
+        },
+        JsonValue::Boolean(b) => Some(Value::Boolean(*b)),
+        JsonValue::Array(items) => Some(Value::Array(items.iter().filter_map(json_to_toml).collect())),
+        JsonValue::Object(object) => Some(Value::Table(
+            object.iter().filter_map(|(key, value)| Some((key.to_string(), json_to_toml(value)?))).collect()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the tables of a config, written to a temporary file as read_config reads a path
+    fn read_tables(name: &str, tables: &str) -> HashMap<String, SqlTableSchema> {
+        let path = std::env::temp_dir().join(format!("rest_api_{}_{}.toml", name, std::process::id()));
+        std::fs::write(&path, format!("database=\"sqlite3\"\n\n{}", tables)).unwrap();
+        let (_, tables) = read_config(path.to_str());
+        std::fs::remove_file(path).unwrap();
+        tables
+    }
+
+    #[test]
+    fn reads_unique_option_of_fields_with_uppercase_names() {
+        let tables = read_tables("unique", "[table.people]\nroute = \"/people\"\nEmail = { type = \"text\", unique = true }\n");
+        let people = &tables["/people"];
+
+        assert_eq!(people.unique, vec!["email"]);
+        assert!(people.field_exists("email"));
+    }
+
+    #[test]
+    fn reads_sizes_greater_than_zero() {
+        assert_eq!(read_positive_size("page_size", &Value::Integer(10)), 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "'max_batch_size' must be greater than 0, found: 0")]
+    fn rejects_sizes_of_zero() {
+        read_positive_size("max_batch_size", &Value::Integer(0));
+    }
+}
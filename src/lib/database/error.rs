@@ -0,0 +1,237 @@
+use std::fmt::Display;
+
+use hyper::{Body, Response, StatusCode};
+use json::object;
+
+use sqlite3::Error as SqlError;
+
+// sqlite3 primary result codes
+const SQLITE_BUSY: isize = 5;
+const SQLITE_LOCKED: isize = 6;
+const SQLITE_TOOBIG: isize = 18;
+pub const SQLITE_CONSTRAINT: isize = 19;
+const SQLITE_MISMATCH: isize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    RouteNotFound,
+    NotFound,
+    MethodNotAllowed,
+    InvalidJson,
+    InvalidBody,
+    InvalidParameter,
+    InvalidValue,
+    MissingField,
+    BulkOperation,
+    BatchTooLarge,
+    ConstraintViolation,
+    InvalidReference,
+    ReferencedRecord,
+    DatabaseBusy,
+    DatabaseError,
+    InternalError,
+}
+
+impl ErrorCode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RouteNotFound => "route_not_found",
+            Self::NotFound => "not_found",
+            Self::MethodNotAllowed => "method_not_allowed",
+            Self::InvalidJson => "invalid_json",
+            Self::InvalidBody => "invalid_body",
+            Self::InvalidParameter => "invalid_parameter",
+            Self::InvalidValue => "invalid_value",
+            Self::MissingField => "missing_field",
+            Self::BulkOperation => "bulk_operation",
+            Self::BatchTooLarge => "batch_too_large",
+            Self::ConstraintViolation => "constraint_violation",
+            Self::InvalidReference => "invalid_reference",
+            Self::ReferencedRecord => "referenced_record",
+            Self::DatabaseBusy => "database_busy",
+            Self::DatabaseError => "database_error",
+            Self::InternalError => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::RouteNotFound | Self::NotFound => StatusCode::NOT_FOUND,
+            Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Self::InvalidJson
+            | Self::InvalidBody
+            | Self::InvalidParameter
+            | Self::InvalidValue
+            | Self::MissingField
+            | Self::BulkOperation => StatusCode::BAD_REQUEST,
+            Self::BatchTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::ConstraintViolation | Self::ReferencedRecord => StatusCode::CONFLICT,
+            Self::InvalidReference => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::DatabaseBusy => StatusCode::SERVICE_UNAVAILABLE,
+            Self::DatabaseError | Self::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// An error handling a request, returned to the client as json
+#[derive(Debug)]
+pub struct QueryErr {
+    pub code: ErrorCode,
+    pub message: String,
+    // the field that caused the error
+    pub field: Option<String>,
+    // methods allowed at the route, sent in the Allow header of method_not_allowed errors
+    pub allow: Option<&'static str>,
+}
+
+impl QueryErr {
+    pub fn new(code: ErrorCode, message: String) -> Self {
+        Self {
+            code,
+            message,
+            field: None,
+            allow: None
+        }
+    }
+
+    pub fn with_field(mut self, field: &str) -> Self {
+        self.field = Some(field.to_string());
+        self
+    }
+
+    pub fn with_allow(mut self, allow: &'static str) -> Self {
+        self.allow = Some(allow);
+        self
+    }
+
+    // the server is at fault, rather than the request
+    pub fn server_fault(&self) -> bool {
+        self.code.status().is_server_error()
+    }
+
+    pub fn to_response(&self, request_id: Option<&str>) -> Response<Body> {
+        // details of unexpected database errors are logged, not returned to the client
+        let message = match self.code {
+            ErrorCode::DatabaseError => "Server Error Encountered",
+            _ => self.message.as_str()
+        };
+        let mut error = object!{
+            code: self.code.name(),
+            message: message,
+        };
+        if let Some(field) = &self.field {
+            error["field"] = field.as_str().into();
+        }
+        if let Some(request_id) = request_id {
+            error["request_id"] = request_id.into();
+        }
+
+        let mut response = Response::builder()
+            .status(self.code.status())
+            .header("Content-Type", "application/json");
+        if let Some(allow) = self.allow {
+            response = response.header("Allow", allow);
+        }
+        response.body(Body::from(object!{error: error}.dump())).unwrap()
+    }
+}
+
+impl Display for QueryErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+impl From<SqlError> for QueryErr {
+    fn from(error: SqlError) -> Self {
+        let message = error.message.unwrap_or_else(|| "Unknown database error".to_string());
+
+        // messages of errors caused by a row in a batch start with the row's index
+        let code = match error.code {
+            // a value doesn't satisfy a field's check, rather than conflicting with other records
+            Some(SQLITE_CONSTRAINT) if message.contains("CHECK constraint failed") => ErrorCode::InvalidValue,
+            // a record can't be deleted while other records reference it
+            // writes that reference a record that doesn't exist are converted to InvalidReference by the query
+            Some(SQLITE_CONSTRAINT) if message.contains("FOREIGN KEY constraint failed") => ErrorCode::ReferencedRecord,
+            Some(SQLITE_CONSTRAINT) => ErrorCode::ConstraintViolation,
+            Some(SQLITE_BUSY) | Some(SQLITE_LOCKED) => ErrorCode::DatabaseBusy,
+            Some(SQLITE_MISMATCH) | Some(SQLITE_TOOBIG) => ErrorCode::InvalidValue,
+            _ => ErrorCode::DatabaseError,
+        };
+
+        // foreign key messages don't say which records are involved
+        if code == ErrorCode::ReferencedRecord {
+            return Self::new(code, message.replace("FOREIGN KEY constraint failed", "Record is referenced by other records"))
+        }
+
+        // constraint messages end with the column, e.g. 'UNIQUE constraint failed: people.name'
+        let field = match code {
+            ErrorCode::ConstraintViolation => message
+                .rsplit_once(": ")
+                .and_then(|(_, column)| column.split_once('.'))
+                .map(|(_, field)| field.to_string())
+                .filter(|field| !field.contains(',')),
+            _ => None
+        };
+
+        Self {
+            code,
+            message,
+            field,
+            allow: None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::to_bytes;
+
+    fn sql_error(code: isize, message: &str) -> QueryErr {
+        QueryErr::from(SqlError {code: Some(code), message: Some(message.to_string())})
+    }
+
+    #[test]
+    fn codes_have_the_status_of_their_cause() {
+        assert_eq!(ErrorCode::NotFound.status(), StatusCode::NOT_FOUND);
+        assert_eq!(ErrorCode::MethodNotAllowed.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(ErrorCode::InvalidParameter.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(ErrorCode::BulkOperation.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(ErrorCode::BatchTooLarge.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(ErrorCode::ConstraintViolation.status(), StatusCode::CONFLICT);
+        assert_eq!(ErrorCode::InvalidReference.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(ErrorCode::DatabaseBusy.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(ErrorCode::DatabaseError.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn maps_database_errors_to_codes() {
+        let unique = sql_error(SQLITE_CONSTRAINT, "UNIQUE constraint failed: people.name");
+        assert_eq!(unique.code, ErrorCode::ConstraintViolation);
+        assert_eq!(unique.field.as_deref(), Some("name"));
+
+        // several columns aren't one field
+        let unique = sql_error(SQLITE_CONSTRAINT, "UNIQUE constraint failed: people.name, people.age");
+        assert_eq!(unique.field, None);
+
+        assert_eq!(sql_error(SQLITE_CONSTRAINT, "CHECK constraint failed: age > 0").code, ErrorCode::InvalidValue);
+        assert_eq!(sql_error(SQLITE_CONSTRAINT, "FOREIGN KEY constraint failed").code, ErrorCode::ReferencedRecord);
+        assert_eq!(sql_error(SQLITE_BUSY, "database is locked").code, ErrorCode::DatabaseBusy);
+        assert_eq!(sql_error(1, "no such table: people").code, ErrorCode::DatabaseError);
+    }
+
+    #[tokio::test]
+    async fn responses_hide_details_of_database_errors() {
+        let error = sql_error(1, "no such table: people");
+        assert_eq!(error.to_string(), "no such table: people");
+
+        let response = error.to_response(Some("1"));
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            json::parse(std::str::from_utf8(&body).unwrap()).unwrap(),
+            object!{error: {code: "database_error", message: "Server Error Encountered", request_id: "1"}}
+        );
+    }
+}
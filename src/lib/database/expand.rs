@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+
+use sqlite3::Connection;
+use sqlite3::Result as SqlResult;
+use sqlite3::Value as SqlValue;
+use sqlite3::Error as SqlError;
+use json::JsonValue;
+
+use super::table_schema::{SqlTableSchema, Relation, RelationKind, MAX_EXPAND_DEPTH};
+use super::query::prepare_rows;
+use super::response::{row_to_json, ResponseFormat};
+use super::error::{QueryErr, ErrorCode};
+use super::filter::id_list;
+
+// a relation whose records are embedded in each row returned by a GET
+// e.g. expand=company embeds each person's company
+pub struct Expansion<'a> {
+    pub relation: &'a Relation,
+    // fields of the related records to return, all fields if empty
+    pub fields: Vec<String>,
+    // relations embedded in each related record
+    pub expand: Vec<Expansion<'a>>,
+}
+
+// parses a comma separated list of relations, where relations of related records follow a '.'
+// e.g. company,employees.company
+pub fn parse_expand<'a>(value: &str, table: &'a SqlTableSchema) -> Result<Vec<Expansion<'a>>, QueryErr> {
+    let mut expand = Vec::new();
+
+    for path in value.split(',') {
+        let names: Vec<&str> = path.split('.').collect();
+        if names.len() > MAX_EXPAND_DEPTH {
+            return Err(QueryErr::new(
+                ErrorCode::InvalidParameter,
+                format!("Can't expand relations more than {} deep: {}", MAX_EXPAND_DEPTH, path)
+            ))
+        }
+
+        let mut level: &mut Vec<Expansion> = &mut expand;
+        let mut level_table = table;
+        for name in names {
+            // prevent sql injection by only allowing relations of the table
+            let relation = level_table.relation(name);
+            if relation.is_none() {
+                return Err(QueryErr::new(ErrorCode::InvalidParameter, format!("Can't expand invalid relation: {}", path)))
+            }
+            let relation = relation.unwrap();
+
+            let index = match level.iter().position(|expansion| expansion.relation.name == name) {
+                Some(index) => index,
+                None => {
+                    level.push(Expansion {relation, fields: Vec::new(), expand: Vec::new()});
+                    level.len() - 1
+                }
+            };
+            level_table = &relation.table;
+            level = &mut level[index].expand;
+        }
+    }
+
+    Ok(expand)
+}
+
+// the expansion of a path of relations, e.g. employees.company
+pub fn find_expansion<'a, 'b>(expand: &'b mut [Expansion<'a>], path: &str) -> Option<&'b mut Expansion<'a>> {
+    let (name, rest) = match path.split_once('.') {
+        Some((name, rest)) => (name, Some(rest)),
+        None => (path, None)
+    };
+
+    let expansion = expand.iter_mut().find(|expansion| expansion.relation.name == name)?;
+    match rest {
+        Some(rest) => find_expansion(&mut expansion.expand, rest),
+        None => Some(expansion)
+    }
+}
+
+// columns used to find related records that aren't requested fields, and the key column if given
+// they are selected after the requested fields, and removed once related records are embedded
+pub fn hidden_fields(fields: &[String], expand: &[Expansion], key: Option<&str>) -> Vec<String> {
+    // every column is selected if no fields are requested
+    if fields.is_empty() {
+        return Vec::new()
+    }
+
+    let needed = std::iter::once("id")
+        .chain(key)
+        .chain(expand.iter()
+            .filter(|expansion| expansion.relation.kind == RelationKind::ManyToOne)
+            .map(|expansion| expansion.relation.field.as_str()));
+
+    let mut hidden: Vec<String> = Vec::new();
+    for column in needed {
+        if !fields.iter().any(|f| f == column) && !hidden.iter().any(|h| h == column) {
+            hidden.push(column.to_string());
+        }
+    }
+    hidden
+}
+
+// embeds the related records of each row, as json in the response format
+// the last hidden columns of each row are used to find related records, then removed
+pub fn expand_rows(
+    connection: &Connection,
+    expand: &[Expansion],
+    rows: &mut [Vec<SqlValue>],
+    columns: &mut Vec<String>,
+    hidden: usize,
+    format: ResponseFormat
+) -> SqlResult<()> {
+    let mut related = Vec::new();
+    for expansion in expand {
+        related.push(load_related(connection, expansion, rows, columns, format)?);
+    }
+
+    columns.truncate(columns.len() - hidden);
+    for row in rows.iter_mut() {
+        row.truncate(row.len() - hidden);
+    }
+
+    // the response builder converts the json of a relation's column back to json
+    for (expansion, values) in expand.iter().zip(related) {
+        let name = &expansion.relation.name;
+        // a relation named the same as its field replaces the field's value
+        let index = match columns.iter().position(|column| column == name) {
+            Some(index) => index,
+            None => {
+                columns.push(name.clone());
+                for row in rows.iter_mut() {
+                    row.push(SqlValue::Null);
+                }
+                columns.len() - 1
+            }
+        };
+        for (row, value) in rows.iter_mut().zip(values) {
+            row[index] = SqlValue::String(value.dump());
+        }
+    }
+
+    Ok(())
+}
+
+// the related records of each row, an object or null for ManyToOne, an array for OneToMany
+// records related to every row are selected by one query, rather than a query for each row
+fn load_related(
+    connection: &Connection,
+    expansion: &Expansion,
+    rows: &[Vec<SqlValue>],
+    columns: &[String],
+    format: ResponseFormat
+) -> SqlResult<Vec<JsonValue>> {
+    let relation = expansion.relation;
+
+    // the column of the rows, and the column of the related table, that have the same id
+    let (key_column, related_column) = match relation.kind {
+        RelationKind::ManyToOne => (relation.field.as_str(), "id"),
+        RelationKind::OneToMany => ("id", relation.field.as_str()),
+    };
+    let key_index = column_index(columns, key_column)?;
+
+    let mut keys: Vec<i64> = rows.iter()
+        .filter_map(|row| match row.get(key_index) {
+            Some(SqlValue::Integer(key)) => Some(*key),
+            _ => None
+        })
+        .collect();
+    keys.sort_unstable();
+    keys.dedup();
+
+    // value of the related column: records with the value
+    let mut related: HashMap<i64, Vec<JsonValue>> = HashMap::new();
+    if !keys.is_empty() {
+        let hidden = hidden_fields(&expansion.fields, &expansion.expand, Some(related_column));
+        // fields MUST be checked to be valid for the related table when constructing query object
+        // or vulnerable to SQL injection
+        let mut select = match expansion.fields.is_empty() {
+            true => "*".to_string(),
+            false => expansion.fields.join(", "),
+        };
+        for column in &hidden {
+            select.push_str(&format!(", {}", column));
+        }
+
+        let sql = format!(
+            "SELECT {} FROM {} WHERE {} IN ({}) ORDER BY id",
+            select, relation.table.name, related_column, id_list(&keys)
+        );
+
+        let mut selected = prepare_rows(connection, sql, &[])?;
+        let mut related_rows = Vec::new();
+        while let Some(row) = selected.next_row()? {
+            related_rows.push(row);
+        }
+        let mut related_columns = selected.columns;
+
+        // the related column may be hidden, so is read before hidden columns are removed
+        let related_index = column_index(&related_columns, related_column)?;
+        let related_keys: Vec<Option<i64>> = related_rows.iter()
+            .map(|row| match row.get(related_index) {
+                Some(SqlValue::Integer(key)) => Some(*key),
+                _ => None
+            })
+            .collect();
+
+        expand_rows(connection, &expansion.expand, &mut related_rows, &mut related_columns, hidden.len(), format)?;
+
+        for (row, key) in related_rows.into_iter().zip(related_keys) {
+            if let Some(key) = key {
+                related.entry(key).or_default().push(row_to_json(row, &related_columns, &relation.table, format));
+            }
+        }
+    }
+
+    Ok(rows.iter()
+        .map(|row| {
+            let records = match row.get(key_index) {
+                Some(SqlValue::Integer(key)) => related.get(key),
+                _ => None
+            };
+            match relation.kind {
+                RelationKind::ManyToOne => records.and_then(|records| records.first()).cloned().unwrap_or(JsonValue::Null),
+                RelationKind::OneToMany => JsonValue::Array(records.cloned().unwrap_or_default()),
+            }
+        })
+        .collect())
+}
+
+fn column_index(columns: &[String], column: &str) -> SqlResult<usize> {
+    columns.iter()
+        .position(|c| c == column)
+        .ok_or_else(|| SqlError {code: None, message: Some(format!("Column {} isn't selected", column))})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::interfaces::SQLType;
+
+    fn companies() -> SqlTableSchema {
+        SqlTableSchema::new("companies", vec![("name".to_string(), SQLType::Text)])
+    }
+
+    fn people() -> SqlTableSchema {
+        SqlTableSchema::new("people", vec![
+            ("name".to_string(), SQLType::Text),
+            ("company_id".to_string(), SQLType::Integer),
+        ])
+    }
+
+    // companies 1 and 2, and people whose company_id are the given values, in order
+    // the tables are related by people.company_id
+    fn database(company_ids: &[&str]) -> (Connection, SqlTableSchema, SqlTableSchema) {
+        let connection = sqlite3::open(":memory:").unwrap();
+        connection.execute("
+            CREATE TABLE companies (id INTEGER PRIMARY KEY, name TEXT);
+            CREATE TABLE people (id INTEGER PRIMARY KEY, name TEXT, company_id INTEGER REFERENCES companies(id));
+            INSERT INTO companies (name) VALUES ('a'), ('b');
+        ").unwrap();
+        for (i, company_id) in company_ids.iter().enumerate() {
+            connection.execute(format!("INSERT INTO people (name, company_id) VALUES ('{}', {});", i, company_id)).unwrap();
+        }
+
+        let mut related_companies = companies();
+        related_companies.relations.push(Relation {
+            name: "people".to_string(),
+            kind: RelationKind::OneToMany,
+            field: "company_id".to_string(),
+            table: people(),
+        });
+        let mut related_people = people();
+        related_people.relations.push(Relation {
+            name: "company".to_string(),
+            kind: RelationKind::ManyToOne,
+            field: "company_id".to_string(),
+            table: companies(),
+        });
+        (connection, related_companies, related_people)
+    }
+
+    // every row of the table, with the relations expanded
+    fn expanded(connection: &Connection, table: &SqlTableSchema, expand: &str) -> Vec<JsonValue> {
+        let expand = parse_expand(expand, table).unwrap();
+        let mut selected = prepare_rows(connection, format!("SELECT * FROM {} ORDER BY id", table.name), &[]).unwrap();
+        let mut rows = Vec::new();
+        while let Some(row) = selected.next_row().unwrap() {
+            rows.push(row);
+        }
+        let mut columns = selected.columns;
+
+        expand_rows(connection, &expand, &mut rows, &mut columns, 0, ResponseFormat::Object).unwrap();
+        rows.into_iter().map(|row| row_to_json(row, &columns, table, ResponseFormat::Object)).collect()
+    }
+
+    #[test]
+    fn expands_many_to_one_after_a_null_reference() {
+        let (connection, _, people) = database(&["NULL", "1", "2"]);
+        let companies: Vec<JsonValue> = expanded(&connection, &people, "company")
+            .into_iter()
+            .map(|person| person["company"]["name"].clone())
+            .collect();
+
+        assert_eq!(companies, vec![JsonValue::Null, JsonValue::from("a"), JsonValue::from("b")]);
+    }
+
+    #[test]
+    fn expands_many_to_one_before_a_null_reference() {
+        let (connection, _, people) = database(&["2", "NULL"]);
+        let people = expanded(&connection, &people, "company");
+
+        assert_eq!(people[0]["company"]["name"], "b");
+        assert!(people[1]["company"].is_null());
+    }
+
+    #[test]
+    fn expands_one_to_many_for_records_without_related_records() {
+        let (connection, companies, _) = database(&["NULL", "2", "2"]);
+        let companies = expanded(&connection, &companies, "people");
+
+        assert_eq!(companies[0]["people"].len(), 0);
+        assert_eq!(companies[1]["people"].len(), 2);
+        assert_eq!(companies[1]["people"][0]["company_id"], 2);
+    }
+
+    #[test]
+    fn rejects_unknown_relations() {
+        let people = people();
+        assert!(parse_expand("team", &people).is_err());
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+
+use sqlite3::Value as SqlValue;
+use json::JsonValue;
+
+use super::table_schema::SqlTableSchema;
+use super::error::{QueryErr, ErrorCode};
+use super::value::json_to_sql_value;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterOperator {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Like,
+    In,
+    IsNull,
+    IsNotNull,
+    Between,
+}
+
+impl FilterOperator {
+    // 'is' is converted to IsNull or IsNotNull when the value is known
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "eq" => Some(Self::Eq),
+            "ne" => Some(Self::Ne),
+            "gt" => Some(Self::Gt),
+            "gte" => Some(Self::Gte),
+            "lt" => Some(Self::Lt),
+            "lte" => Some(Self::Lte),
+            "like" => Some(Self::Like),
+            "in" => Some(Self::In),
+            "is" => Some(Self::IsNull),
+            "between" => Some(Self::Between),
+            _ => None
+        }
+    }
+}
+
+// A single condition in a WHERE clause, e.g. age[gt]=30
+#[derive(Clone, Debug)]
+pub struct Filter {
+    pub field: String,
+    pub operator: FilterOperator,
+    // values bound to the condition, of the field's type
+    pub values: Vec<SqlValue>,
+    // compare text ignoring case, from an operator starting with 'i', e.g. name[ieq]=john
+    // or a like filter of a field with the NOCASE collation
+    pub case_insensitive: bool,
+}
+
+impl Filter {
+    pub fn new(field: &str, operator: FilterOperator, values: Vec<SqlValue>) -> Self {
+        Self {
+            field: field.to_string(),
+            operator,
+            values,
+            case_insensitive: false
+        }
+    }
+
+    // parses a key in the form 'field' or 'field[operator]'
+    // values from query strings are json strings, and are converted to the field's type
+    pub fn parse(key: &str, value: &JsonValue, table: &SqlTableSchema) -> Result<Self, QueryErr> {
+        let (field, operator, case_insensitive) = match key.split_once('[') {
+            None => (key, FilterOperator::Eq, false),
+            Some((field, operator)) => {
+                let operator_name = operator.strip_suffix(']');
+                if operator_name.is_none() {
+                    return Err(QueryErr::new(ErrorCode::InvalidParameter, format!("Invalid filter: {}", key)))
+                }
+                let operator_name = operator_name.unwrap();
+
+                // any operator other than 'is' can start with 'i' to ignore case, e.g. ieq, iin
+                let operator = match FilterOperator::from_name(operator_name) {
+                    Some(operator) => Some((operator, false)),
+                    None => operator_name.strip_prefix('i')
+                        .and_then(FilterOperator::from_name)
+                        .filter(|operator| *operator != FilterOperator::IsNull)
+                        .map(|operator| (operator, true))
+                };
+                if operator.is_none() {
+                    return Err(QueryErr::new(ErrorCode::InvalidParameter, format!("Invalid filter operator: {}", key)))
+                }
+                let (operator, case_insensitive) = operator.unwrap();
+                (field, operator, case_insensitive)
+            }
+        };
+
+        // prevent sql injection by only allowing valid field names
+        // a misspelled field is an error, rather than a filter that is ignored and matches every record
+        let sql_type = match table.field_type(field) {
+            Some(sql_type) => sql_type,
+            None => return Err(QueryErr::new(ErrorCode::InvalidParameter, format!("Can't filter by invalid field: {}", field)).with_field(field))
+        };
+
+        // LIKE ignores case whatever the collation, so patterns only ignore case if asked to, or if the field does
+        let case_insensitive = case_insensitive || (
+            operator == FilterOperator::Like
+                && table.field_options(field).and_then(|options| options.collate.as_deref()) == Some("NOCASE")
+        );
+
+        let invalid_value = || QueryErr::new(
+            ErrorCode::InvalidValue,
+            format!("Invalid value for filter on {}: {}", field, value.dump())
+        ).with_field(field);
+
+        let (operator, values) = match operator {
+            FilterOperator::IsNull => {
+                let operator = match value.as_str().map(str::to_ascii_lowercase).as_deref() {
+                    _ if value.is_null() => FilterOperator::IsNull,
+                    Some("null") => FilterOperator::IsNull,
+                    Some("notnull") => FilterOperator::IsNotNull,
+                    _ => return Err(invalid_value())
+                };
+                (operator, vec![])
+            },
+            FilterOperator::In | FilterOperator::Between => {
+                // a json array, or a comma separated string
+                let items: Vec<JsonValue> = match value {
+                    JsonValue::Array(items) => items.clone(),
+                    _ => match value.as_str() {
+                        Some(list) => list.split(',').map(JsonValue::from).collect(),
+                        None => vec![value.clone()]
+                    }
+                };
+
+                let valid = match operator {
+                    FilterOperator::Between => items.len() == 2,
+                    _ => !items.is_empty() && !items.iter().any(|item| item.as_str() == Some(""))
+                };
+                if !valid {
+                    return Err(invalid_value())
+                }
+
+                let mut values = Vec::new();
+                for item in &items {
+                    values.push(json_to_sql_value(field, sql_type, item)?);
+                }
+                (operator, values)
+            },
+            // patterns are always text
+            FilterOperator::Like => match value.as_str() {
+                Some(pattern) if case_insensitive => (operator, vec![SqlValue::String(pattern.to_string())]),
+                Some(pattern) => (operator, vec![SqlValue::String(like_to_glob(pattern))]),
+                None => return Err(invalid_value())
+            },
+            // = NULL never matches
+            FilterOperator::Eq if value.is_null() => (FilterOperator::IsNull, vec![]),
+            _ => (operator, vec![json_to_sql_value(field, sql_type, value)?])
+        };
+
+        Ok(Self {
+            field: field.to_string(),
+            operator,
+            values,
+            case_insensitive
+        })
+    }
+
+    // SQL for the condition, pushing any values to bindings
+    pub fn to_sql(&self, bindings: &mut Vec<SqlValue>) -> String {
+        bindings.extend(self.values.iter().cloned());
+
+        // fields MUST be checked to be valid for the table when constructing the filter
+        // or vulnerable to SQL injection
+        // the collation of the field overrides the collation of the column
+        let field = match self.case_insensitive {
+            true => format!("{} COLLATE NOCASE", self.field),
+            false => self.field.clone()
+        };
+
+        match self.operator {
+            FilterOperator::Eq => format!("{}=?", field),
+            FilterOperator::Ne => format!("{}!=?", field),
+            FilterOperator::Gt => format!("{}>?", field),
+            FilterOperator::Gte => format!("{}>=?", field),
+            FilterOperator::Lt => format!("{}<?", field),
+            FilterOperator::Lte => format!("{}<=?", field),
+            // case-sensitive patterns are converted to GLOB patterns when parsed
+            FilterOperator::Like if self.case_insensitive => format!("{} LIKE ?", field),
+            FilterOperator::Like => format!("{} GLOB ?", field),
+            FilterOperator::IsNull => format!("{} IS NULL", field),
+            FilterOperator::IsNotNull => format!("{} IS NOT NULL", field),
+            FilterOperator::In => format!("{} IN ({})", field, vec!["?"; self.values.len()].join(",")),
+            FilterOperator::Between => format!("{} BETWEEN ? AND ?", field),
+        }
+    }
+}
+
+// a LIKE pattern as a GLOB pattern, which is case-sensitive
+// '%' and '_' are wildcards, and GLOB's wildcards are matched as they are
+fn like_to_glob(pattern: &str) -> String {
+    let mut glob = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        match c {
+            '%' => glob.push('*'),
+            '_' => glob.push('?'),
+            '*' | '?' | '[' => glob.push_str(&format!("[{}]", c)),
+            _ => glob.push(c)
+        }
+    }
+    glob
+}
+
+// repeated equality filters on a field match any of their values, as a field can't equal all of them
+// e.g. name=a&name=b is the same as name[in]=a,b
+pub fn merge_repeated(filters: Vec<Filter>) -> Vec<Filter> {
+    let mut merged: Vec<Filter> = Vec::new();
+    // (field, case insensitive): index of its equality filter in merged
+    let mut equal = HashMap::new();
+
+    for filter in filters {
+        if filter.operator != FilterOperator::Eq {
+            merged.push(filter);
+            continue
+        }
+
+        let key = (filter.field.clone(), filter.case_insensitive);
+        match equal.get(&key) {
+            Some(&index) => {
+                let existing: &mut Filter = &mut merged[index];
+                existing.operator = FilterOperator::In;
+                existing.values.extend(filter.values);
+            },
+            None => {
+                equal.insert(key, merged.len());
+                merged.push(filter);
+            }
+        }
+    }
+
+    merged
+}
+
+// a WHERE clause joining all filters with AND, or an empty string if there are no filters
+pub fn where_clause(filters: &[Filter], bindings: &mut Vec<SqlValue>) -> String {
+    if filters.is_empty() {
+        return String::new()
+    }
+
+    let conditions: Vec<String> = filters.iter().map(|f| f.to_sql(bindings)).collect();
+    format!(" WHERE {}", conditions.join(" AND "))
+}
+
+// ids, or other keys, as a list for 'IN (...)'
+// they are integers from the database, so can't be used for SQL injection
+// and aren't bound, so any number of rows can be selected
+pub fn id_list(ids: &[i64]) -> String {
+    ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::interfaces::SQLType;
+    use super::super::table_schema::FieldOptions;
+
+    // people with a name, a nocase nickname, and an age
+    fn people() -> SqlTableSchema {
+        let mut people = SqlTableSchema::new("people", vec![
+            ("name".to_string(), SQLType::Text),
+            ("nickname".to_string(), SQLType::Text),
+            ("age".to_string(), SQLType::Integer),
+        ]);
+        people.field_options.insert("nickname".to_string(), FieldOptions {collate: Some("NOCASE".to_string()), ..FieldOptions::default()});
+        people
+    }
+
+    fn parse(key: &str, value: &str) -> Result<Filter, QueryErr> {
+        Filter::parse(key, &JsonValue::from(value), &people())
+    }
+
+    // (SQL, bindings) of a filter
+    fn sql(key: &str, value: &str) -> (String, Vec<SqlValue>) {
+        let mut bindings = Vec::new();
+        let sql = parse(key, value).unwrap().to_sql(&mut bindings);
+        (sql, bindings)
+    }
+
+    fn filters(pairs: &[(&str, &str)]) -> Vec<Filter> {
+        pairs.iter().map(|(key, value)| parse(key, value).unwrap()).collect()
+    }
+
+    fn error_code(key: &str, value: &str) -> ErrorCode {
+        parse(key, value).err().unwrap().code
+    }
+
+    #[test]
+    fn rejects_invalid_operators() {
+        assert_eq!(error_code("name[bad]", "a"), ErrorCode::InvalidParameter);
+        assert_eq!(error_code("name[gt", "a"), ErrorCode::InvalidParameter);
+        assert_eq!(error_code("name[]", "a"), ErrorCode::InvalidParameter);
+        // 'is' can't ignore case
+        assert_eq!(error_code("name[iis]", "null"), ErrorCode::InvalidParameter);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        for key in ["height", "height[gt]", "nmae"] {
+            let error = parse(key, "1").err().unwrap();
+            assert_eq!(error.code, ErrorCode::InvalidParameter);
+            assert_eq!(error.field.as_deref(), key.split('[').next());
+        }
+    }
+
+    #[test]
+    fn converts_values_to_the_type_of_the_field() {
+        assert_eq!(sql("age[gte]", "3"), ("age>=?".to_string(), vec![SqlValue::Integer(3)]));
+        assert_eq!(error_code("age", "three"), ErrorCode::InvalidValue);
+    }
+
+    #[test]
+    fn splits_lists_at_commas() {
+        assert_eq!(sql("age[in]", "1,2,3"), (
+            "age IN (?,?,?)".to_string(),
+            vec![SqlValue::Integer(1), SqlValue::Integer(2), SqlValue::Integer(3)]
+        ));
+        assert_eq!(sql("name[in]", "a"), ("name IN (?)".to_string(), vec![SqlValue::String("a".to_string())]));
+        assert_eq!(error_code("age[in]", "1,,2"), ErrorCode::InvalidValue);
+        assert_eq!(error_code("age[in]", ""), ErrorCode::InvalidValue);
+        assert_eq!(error_code("age[in]", "1,x"), ErrorCode::InvalidValue);
+    }
+
+    #[test]
+    fn between_needs_two_values() {
+        assert_eq!(sql("age[between]", "1,5"), (
+            "age BETWEEN ? AND ?".to_string(),
+            vec![SqlValue::Integer(1), SqlValue::Integer(5)]
+        ));
+        assert_eq!(error_code("age[between]", "1"), ErrorCode::InvalidValue);
+        assert_eq!(error_code("age[between]", "1,5,9"), ErrorCode::InvalidValue);
+        assert!(Filter::parse("age[between]", &json::array![1, 5], &people()).is_ok());
+    }
+
+    #[test]
+    fn is_checks_for_null() {
+        assert_eq!(sql("age[is]", "null").0, "age IS NULL");
+        assert_eq!(sql("age[is]", "NotNull").0, "age IS NOT NULL");
+        assert_eq!(error_code("age[is]", "1"), ErrorCode::InvalidValue);
+        assert_eq!(Filter::parse("age", &JsonValue::Null, &people()).unwrap().operator, FilterOperator::IsNull);
+    }
+
+    #[test]
+    fn merges_repeated_equal_filters_into_in() {
+        let merged = merge_repeated(filters(&[("name", "a"), ("age[gt]", "3"), ("name", "b"), ("name", "c")]));
+
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].operator, FilterOperator::In);
+        assert_eq!(merged[0].values, vec![
+            SqlValue::String("a".to_string()),
+            SqlValue::String("b".to_string()),
+            SqlValue::String("c".to_string())
+        ]);
+        assert_eq!(merged[1].operator, FilterOperator::Gt);
+    }
+
+    #[test]
+    fn merges_only_filters_with_the_same_field_and_case() {
+        let merged = merge_repeated(filters(&[("name", "a"), ("name[ieq]", "b"), ("age", "1"), ("age[gt]", "0"), ("age[gt]", "2")]));
+        let operators: Vec<&FilterOperator> = merged.iter().map(|filter| &filter.operator).collect();
+
+        assert_eq!(operators, vec![
+            &FilterOperator::Eq, &FilterOperator::Eq, &FilterOperator::Eq, &FilterOperator::Gt, &FilterOperator::Gt
+        ]);
+        assert!(merged[1].case_insensitive);
+    }
+
+    #[test]
+    fn case_insensitive_operators_compare_with_nocase() {
+        assert_eq!(sql("name[ieq]", "Jo"), ("name COLLATE NOCASE=?".to_string(), vec![SqlValue::String("Jo".to_string())]));
+        assert_eq!(sql("name[iin]", "a,B").0, "name COLLATE NOCASE IN (?,?)");
+    }
+
+    #[test]
+    fn like_is_case_sensitive() {
+        assert_eq!(sql("name[like]", "Jo%_"), ("name GLOB ?".to_string(), vec![SqlValue::String("Jo*?".to_string())]));
+        // GLOB wildcards in a pattern are matched as they are
+        assert_eq!(sql("name[like]", "a*b?[c]"), ("name GLOB ?".to_string(), vec![SqlValue::String("a[*]b[?][[]c]".to_string())]));
+    }
+
+    #[test]
+    fn ilike_and_nocase_fields_ignore_case() {
+        assert_eq!(sql("name[ilike]", "jo%"), ("name COLLATE NOCASE LIKE ?".to_string(), vec![SqlValue::String("jo%".to_string())]));
+        assert_eq!(sql("nickname[like]", "jo%"), ("nickname COLLATE NOCASE LIKE ?".to_string(), vec![SqlValue::String("jo%".to_string())]));
+    }
+
+    #[test]
+    fn lists_ids_for_in() {
+        assert_eq!(id_list(&[1, -2, 30]), "1, -2, 30");
+        assert_eq!(id_list(&[]), "");
+    }
+}
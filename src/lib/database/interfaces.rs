@@ -1,13 +1,27 @@
 use std::collections::HashMap;
 use std::path::Path;
 use std::fs;
+use std::sync::{Mutex, MutexGuard};
 
-use super::table_schema::SqlTableSchema;
-use super::response::{Sqlite3ResponseBuilder, ResponseBuilder};
-use super::query::{Sqlite3Query, Query};
+use super::table_schema::{SqlTableSchema, FieldOptions, Index};
+use super::response::{Sqlite3ResponseBuilder, ResponseBuilder, ResponseFormat};
+use super::query::{Sqlite3Query, Query, HttpMethod, ReturnPreference};
+use super::pagination::{next_page_uri, NextPage};
+use super::error::{QueryErr, ErrorCode};
+use super::value::sql_literal;
+use super::migration::{
+    TableChanges, existing_columns, can_add_column, add_column, rebuild_table, create_migrations_table, record_migration,
+    created_indexes, record_index, drop_created_index
+};
+use super::super::app::RequestId;
+use super::super::api_http_server::routing::split_uri_args;
 
 use sqlite3::{open, Connection};
-use hyper::{Body, Request, Response};
+use sqlite3::Value as SqlValue;
+use sqlite3::Result as SqlResult;
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::HeaderValue;
+use json::object;
 
 #[derive(Clone, Debug)]
 pub enum SQLType {
@@ -15,6 +29,15 @@ pub enum SQLType {
     Integer,
     Real,
     Text,
+    // stored as 0 or 1
+    Boolean,
+    // ISO-8601 text
+    Date,
+    DateTime,
+    // base64 in json
+    Blob,
+    // json text
+    Json,
 }
 
 
@@ -30,16 +53,46 @@ pub trait DatabaseInterface {
 
     fn create_tables_from_schemas(&self, schemas: Vec<&SqlTableSchema>) {
         for schema in schemas {
-            self.table_from_types(schema.name.clone(), &schema.fields)
+            self.table_from_types(schema.name.clone(), &schema.fields, &schema.field_options);
+            for column in &schema.unique {
+                self.unique_index(&schema.name, column)
+            }
         }
     }
 
-    fn table_from_types(&self, table_name: String, types: &HashMap<String, SQLType>);
+    // creates tables and columns of the config that aren't in the database, when the server starts
+    // other differences are logged, or applied by rebuilding the table if rebuild is true, which deletes dropped columns
+    fn migrate(&self, schemas: Vec<&SqlTableSchema>, rebuild: bool);
+
+    // indexes are kept the same as the config's every time the server starts, not only when tables are created
+    fn indexes_from_schemas(&self, schemas: Vec<&SqlTableSchema>) {
+        for schema in schemas {
+            self.sync_indexes(&schema.name, &schema.indexes);
+        }
+    }
+
+    // types are (column name, type), in the order the columns are created
+    // options are (column name: options), for columns that have options
+    fn table_from_types(&self, table_name: String, types: &[(String, SQLType)], options: &HashMap<String, FieldOptions>);
+    fn unique_index(&self, table_name: &str, column: &str);
+    // creates the table's indexes, replacing changed indexes and dropping indexes removed from the config
+    fn sync_indexes(&self, table_name: &str, indexes: &[Index]);
     async fn process_api_request(&self, request: &mut Request<Body>, table: &SqlTableSchema) -> Response<Body>;
 }
 
 pub struct SQLite3Interface {
-    connection: Connection,
+    // statements from concurrent requests must not be interleaved
+    // e.g. an INSERT and reading the inserted row
+    connection: Mutex<Connection>,
+    // used when a request doesn't specify a format
+    default_format: ResponseFormat,
+}
+
+impl SQLite3Interface {
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        // a panic while holding the lock doesn't leave the connection in an invalid state
+        self.connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
 }
 
 #[async_trait::async_trait]
@@ -49,41 +102,148 @@ impl DatabaseInterface for SQLite3Interface {
 
         let existing = Path::new(db_path).exists();
 
-        let connection = open(db_path).expect(&format!("Can't open sqlite3 database at: {}", db_path));
+        let connection = open(db_path).unwrap_or_else(|_| panic!("Can't open sqlite3 database at: {}", db_path));
+        // foreign keys are only checked if enabled on each connection
+        connection.execute("PRAGMA foreign_keys = ON").expect("Can't enable foreign keys");
+        
+        let default_format = match config.get("response_format") {
+            Some(format) => ResponseFormat::from_name(format).expect("Invalid 'response_format' config"),
+            None => ResponseFormat::Array
+        };
         
         log::info!("Connected to database at {}", db_path);
         (
             Self {
-                connection
+                connection: Mutex::new(connection),
+                default_format
             }
             , existing
         )
     }
 
-    fn table_from_types(&self, table_name: String, types: &HashMap<String, SQLType>) {
-        let mut sql = format!("CREATE TABLE IF NOT EXISTS {} (ID INTEGER PRIMARY KEY,", table_name);
-
-        for (col_name, data_type) in types {
-            sql.push_str(&col_name);
-            let dtype = match data_type {
-                SQLType::Null => " NULL",
-                SQLType::Integer => " INTEGER",
-                SQLType::Real => " REAL",
-                SQLType::Text => " TEXT",
-            };
-            sql.push_str(dtype);
-            sql.push_str(", ")
-        }
-        // remove last ", "
-        sql.remove(sql.len()-1);
-        sql.remove(sql.len()-1);
-        sql.push_str(");");
+    fn table_from_types(&self, table_name: String, types: &[(String, SQLType)], options: &HashMap<String, FieldOptions>) {
+        let sql = table_sql(&table_name, types, options);
 
         log::info!("Creating table with SQL: {}", sql);
 
-        self.connection.execute(sql).expect("Can't create table");
+        self.connection().execute(sql).expect("Can't create table");
+    }
+
+    fn unique_index(&self, table_name: &str, column: &str) {
+        let name = format!("{}_{}_unique", table_name, column);
+        let sql = format!("CREATE UNIQUE INDEX IF NOT EXISTS {} ON {} ({});", name, table_name, column);
+
+        log::info!("Creating index with SQL: {}", sql);
+
+        let connection = self.connection();
+        connection.execute(sql).expect("Can't create unique index");
+        // recorded so the index is dropped once the field isn't unique
+        record_index(&connection, table_name, &name).expect("Can't record index");
     }
     
+    fn migrate(&self, schemas: Vec<&SqlTableSchema>, rebuild: bool) {
+        create_migrations_table(&self.connection()).expect("Can't create migrations table");
+        let record = |description: String| {
+            log::info!("Migration: {}", description);
+            record_migration(&self.connection(), &description).expect("Can't record migration");
+        };
+
+        for schema in &schemas {
+            let existing = existing_columns(&self.connection(), &schema.name).expect("Can't read columns of table");
+            if existing.is_none() {
+                self.table_from_types(schema.name.clone(), &schema.fields, &schema.field_options);
+                record(format!("Created table {}", schema.name));
+                continue
+            }
+            let existing = existing.unwrap();
+
+            let changes = TableChanges::compare(schema, &existing);
+            if changes.is_empty() {
+                continue
+            }
+
+            if changes.needs_rebuild(schema) && rebuild {
+                rebuild_table(&self.connection(), schema, &existing)
+                    .unwrap_or_else(|e| panic!("Can't rebuild table {}: {:?}", schema.name, e.message));
+                record(format!("Rebuilt table {}: {}", schema.name, changes.describe()));
+                continue
+            }
+
+            // columns that existing rows can have NULL or a default for are always added
+            for field in changes.added.iter().filter(|field| can_add_column(schema, field)) {
+                add_column(&self.connection(), schema, field)
+                    .unwrap_or_else(|e| panic!("Can't add column {}.{}: {:?}", schema.name, field, e.message));
+                record(format!("Added column {}.{}", schema.name, field));
+            }
+
+            if changes.needs_rebuild(schema) {
+                let existing = existing_columns(&self.connection(), &schema.name)
+                    .expect("Can't read columns of table")
+                    .unwrap_or_default();
+                log::warn!(
+                    "Table {} doesn't match the config: {}. Start with --migrate to rebuild the table, which deletes dropped columns",
+                    schema.name,
+                    TableChanges::compare(schema, &existing).describe()
+                );
+            }
+        }
+
+        // unique fields of new or rebuilt tables, and fields that have become unique
+        for schema in schemas {
+            for column in &schema.unique {
+                self.unique_index(&schema.name, column)
+            }
+
+            let removed: Vec<String> = created_indexes(&self.connection(), &schema.name)
+                .expect("Can't read created indexes")
+                .into_iter()
+                .filter(|name| name.ends_with("_unique"))
+                .filter(|name| !schema.unique.iter().any(|column| *name == format!("{}_{}_unique", schema.name, column)))
+                .collect();
+            for name in removed {
+                drop_created_index(&self.connection(), &name).expect("Can't drop index");
+                record(format!("Dropped unique index {}", name));
+            }
+        }
+    }
+
+    fn sync_indexes(&self, table_name: &str, indexes: &[Index]) {
+        let connection = self.connection();
+        let existing = existing_indexes(&connection, table_name).expect("Can't read indexes");
+
+        // only indexes created from the config are dropped, other indexes are kept
+        // unique indexes of fields are kept the same as the config's by migrate
+        let removed = created_indexes(&connection, table_name)
+            .expect("Can't read created indexes")
+            .into_iter()
+            .filter(|name| name.ends_with("_idx") && !indexes.iter().any(|index| index.name == *name));
+        for name in removed {
+            log::info!("Dropping index removed from config: {}", name);
+            drop_created_index(&connection, &name).expect("Can't drop index");
+        }
+
+        for index in indexes {
+            let sql = index_sql(table_name, index);
+            // the database stores the statement that created the index, so a changed index has different SQL
+            let unchanged = match existing.iter().find(|(name, _)| *name == index.name) {
+                Some((_, existing_sql)) if *existing_sql == sql => true,
+                Some((name, _)) => {
+                    log::info!("Dropping changed index: {}", name);
+                    connection.execute(format!("DROP INDEX {};", name)).expect("Can't drop index");
+                    false
+                },
+                None => false
+            };
+
+            if !unchanged {
+                log::info!("Creating index with SQL: {}", sql);
+                connection.execute(&sql).expect("Can't create index");
+            }
+            // an index declared in the config is dropped once it's removed, even if it wasn't created from the config
+            record_index(&connection, table_name, &index.name).expect("Can't record index");
+        }
+    }
+
     fn delete_db(config: &Config) {
         let db_path = config.get("database_path").expect("Can't find 'database_path' config");
         let result = fs::remove_file(db_path);
@@ -95,54 +255,453 @@ impl DatabaseInterface for SQLite3Interface {
     }
 
     async fn process_api_request(&self, request: &mut Request<Body>, table: &SqlTableSchema) -> Response<Body> {
+        let request_id = request.extensions().get::<RequestId>().map(|id| id.0.clone());
+
         // GENERATE QUERY
         let query = Sqlite3Query::from_request(request, table).await;
 
         if query.is_err() {
-            let error = query.err().unwrap();
-
-            if error.1 {
-                log::warn!("{}", error.0);
-                return Response::builder()
-                    .status(500)
-                    .body(Body::from("Server Error Encountered"))
-                    .unwrap();
-            } else {
-                log::debug!("{}", error.0);
-                return Response::builder()
-                    .status(400)
-                    .body(Body::from("Client Error"))
-                    .unwrap();
-            }
+            return error_response(query.err().unwrap(), request_id);
         }
 
         // EXECUTE QUERY
         let query = query.unwrap();
-        let cursor = query.execute_sql(&self.connection);
+        let connection = self.connection();
+        let rows = query.execute_sql(&connection);
 
-        if cursor.is_err() {
-            let error = cursor.err().unwrap();
-            log::warn!("{}", error);
-            
-            return Response::builder()
-                    .status(500)
-                    .body(Body::from("Server Error Encountered"))
-                    .unwrap();
+        if rows.is_err() {
+            return error_response(query.query_error(rows.err().unwrap()), request_id);
         }
 
         // CREATE RESPONSE FROM DATA
         let mut all_data = Vec::new();
-        let mut c = cursor.unwrap();
-        while let Ok(Some(d)) = c.next() {
-            all_data.push(d.to_vec())
+        let mut rows = rows.unwrap();
+        loop {
+            match rows.next_row() {
+                Ok(Some(d)) => all_data.push(d),
+                Ok(None) => break,
+                Err(e) => return error_response(query.query_error(e), request_id)
+            }
         }
+        let mut columns = rows.columns;
+
+        // link to the next page if this page is full
+        let mut next_link = None;
+        if let (Some(limit), Some(last_row)) = (query.limit, all_data.last()) {
+            if all_data.len() == limit {
+                let next_page = match last_row.get(query.id_index()) {
+                    Some(SqlValue::Integer(last_id)) if query.order_by.is_empty() => Some(NextPage::Cursor(*last_id)),
+                    _ if !query.order_by.is_empty() => Some(NextPage::Offset(query.offset.unwrap_or(0) + limit)),
+                    _ => None
+                };
+                next_link = next_page.map(|next_page| next_page_uri(request.uri().to_string(), limit, next_page));
+            }
+        }
+
+        let format = query.format.unwrap_or(self.default_format);
+        if let Err(e) = query.expand_rows(&connection, &mut all_data, &mut columns, format) {
+            return error_response(query.query_error(e), request_id)
+        }
+        let not_found = || error_response(
+            QueryErr::new(ErrorCode::NotFound, "Record not found".to_string()),
+            request_id.clone()
+        );
+
+        match query.method {
+            HttpMethod::GET | HttpMethod::PUT if query.single_record => {
+                match all_data.pop() {
+                    Some(row) => json_response(StatusCode::OK, Sqlite3ResponseBuilder::from_single_row(row, &columns, table, format)),
+                    None => not_found()
+                }
+            },
+            // upserted rows may have been updated rather than created
+            HttpMethod::POST if query.upsert_key.is_some() => {
+                json_response(StatusCode::OK, Sqlite3ResponseBuilder::from_row_data(all_data, &columns, table, format))
+            },
+            HttpMethod::POST => {
+                let mut response = json_response(
+                    StatusCode::CREATED,
+                    Sqlite3ResponseBuilder::from_row_data(all_data.clone(), &columns, table, format)
+                );
 
-        let response_json_text = Sqlite3ResponseBuilder::from_row_data(all_data);
-        Response::new(Body::from(response_json_text))
+                // location of the new record, at the single record route
+                // a batch of records has no single location
+                if let [row] = all_data.as_slice() {
+                    if let Some(SqlValue::Integer(id)) = row.first() {
+                        let (base_uri, _) = split_uri_args(request.uri().to_string());
+                        let location = format!("{}/{}", base_uri.trim_end_matches('/'), id);
+                        if let Ok(location) = HeaderValue::from_str(&location) {
+                            response.headers_mut().insert("Location", location);
+                        }
+                    }
+                }
+                response
+            },
+            HttpMethod::DELETE | HttpMethod::PATCH => {
+                let affected = changes(&connection);
+                if affected.is_err() {
+                    return error_response(affected.err().unwrap().into(), request_id);
+                }
+
+                let affected = affected.unwrap();
+
+                // a single record that doesn't exist can't be deleted or updated
+                if query.single_record && affected == 0 {
+                    return not_found()
+                }
+
+                let mut response = match query.return_preference {
+                    ReturnPreference::Count => json_response(StatusCode::OK, object!{affected: affected}.dump()),
+                    ReturnPreference::Minimal => Response::builder()
+                        .status(StatusCode::NO_CONTENT)
+                        .body(Body::empty())
+                        .unwrap(),
+                    ReturnPreference::Representation if query.single_record => json_response(
+                        StatusCode::OK,
+                        Sqlite3ResponseBuilder::from_single_row(all_data.pop().unwrap_or_default(), &columns, table, format)
+                    ),
+                    ReturnPreference::Representation => json_response(
+                        StatusCode::OK,
+                        Sqlite3ResponseBuilder::from_row_data(all_data, &columns, table, format)
+                    ),
+                };
+                if let Some(applied) = query.return_preference.applied() {
+                    response.headers_mut().insert("Preference-Applied", HeaderValue::from_static(applied));
+                }
+                response
+            },
+            _ => {
+                let mut response = json_response(
+                    StatusCode::OK,
+                    Sqlite3ResponseBuilder::from_row_data(all_data, &columns, table, format)
+                );
+                if let Some(next_link) = next_link.and_then(|link| HeaderValue::from_str(&format!("<{}>; rel=\"next\"", link)).ok()) {
+                    response.headers_mut().insert("Link", next_link);
+                }
+                response
+            }
+        }
     }
 }
 
-// may be negative side effects
-// must be implemented for 'object safety' over threads
-unsafe impl Send for SQLite3Interface {}
-unsafe impl Sync for SQLite3Interface {}
+// CREATE TABLE statement for a table with the fields, and an id primary key
+pub fn table_sql(table_name: &str, types: &[(String, SQLType)], options: &HashMap<String, FieldOptions>) -> String {
+    let mut columns = vec!["ID INTEGER PRIMARY KEY".to_string()];
+    for (col_name, data_type) in types {
+        columns.push(column_definition(col_name, data_type, options.get(col_name)));
+    }
+
+    format!("CREATE TABLE IF NOT EXISTS {} ({});", table_name, columns.join(", "))
+}
+
+// the column's name, type and constraints, as in CREATE TABLE
+pub fn column_definition(col_name: &str, data_type: &SQLType, options: Option<&FieldOptions>) -> String {
+    let mut sql = format!("{} {}", col_name, column_type(data_type));
+
+    if let Some(options) = options {
+        if options.not_null {
+            sql.push_str(" NOT NULL");
+        }
+        if let Some(default) = &options.default {
+            sql.push_str(&format!(" DEFAULT {}", sql_literal(default)));
+        }
+        // checks are SQL from the config, which is trusted
+        if let Some(check) = &options.check {
+            sql.push_str(&format!(" CHECK ({})", check));
+        }
+        // referenced tables MUST be checked to be tables in the config
+        if let Some(table) = &options.references {
+            sql.push_str(&format!(" REFERENCES {}(id)", table));
+            if let Some(on_delete) = &options.on_delete {
+                sql.push_str(&format!(" ON DELETE {}", on_delete));
+            }
+        }
+        // collations MUST be checked to be names when reading the config
+        if let Some(collate) = &options.collate {
+            sql.push_str(&format!(" COLLATE {}", collate));
+        }
+    }
+    sql
+}
+
+// the declared type of a column of the type
+pub fn column_type(data_type: &SQLType) -> &'static str {
+    match data_type {
+        SQLType::Null => "NULL",
+        SQLType::Integer => "INTEGER",
+        SQLType::Real => "REAL",
+        SQLType::Text => "TEXT",
+        SQLType::Boolean => "BOOLEAN",
+        // declared with TEXT, as a type containing TEXT has text affinity
+        // otherwise SQLite stores values that look like numbers as numbers
+        SQLType::Date => "DATE TEXT",
+        SQLType::DateTime => "DATETIME TEXT",
+        SQLType::Blob => "BLOB",
+        SQLType::Json => "JSON TEXT",
+    }
+}
+
+// fields, names and conditions MUST be checked to be valid when reading the config
+// the statement is stored by the database as it is, without a trailing ';', so can be compared with existing indexes
+fn index_sql(table_name: &str, index: &Index) -> String {
+    let mut sql = format!(
+        "CREATE {}INDEX {} ON {} ({})",
+        if index.unique {"UNIQUE "} else {""},
+        index.name,
+        table_name,
+        index.fields.join(", ")
+    );
+    if let Some(condition) = &index.condition {
+        sql.push_str(&format!(" WHERE {}", condition));
+    }
+    sql
+}
+
+// (name, SQL) of the indexes of a table, other than indexes created by the database, e.g. for primary keys
+fn existing_indexes(connection: &Connection, table_name: &str) -> SqlResult<Vec<(String, String)>> {
+    let mut cursor = connection
+        .prepare("SELECT name, sql FROM sqlite_master WHERE type = 'index' AND tbl_name = ? AND sql IS NOT NULL")?
+        .cursor();
+    cursor.bind(&[SqlValue::String(table_name.to_string())])?;
+
+    let mut indexes = Vec::new();
+    while let Some(row) = cursor.next()? {
+        if let [SqlValue::String(name), SqlValue::String(sql)] = row {
+            indexes.push((name.clone(), sql.clone()));
+        }
+    }
+    Ok(indexes)
+}
+
+// number of rows changed by the most recent INSERT, UPDATE or DELETE
+fn changes(connection: &Connection) -> SqlResult<i64> {
+    let mut cursor = connection.prepare("SELECT changes()")?.cursor();
+    match cursor.next()? {
+        Some([SqlValue::Integer(changes)]) => Ok(*changes),
+        _ => Ok(0)
+    }
+}
+
+fn json_response(status: StatusCode, json: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json))
+        .unwrap()
+}
+
+fn error_response(error: QueryErr, request_id: Option<String>) -> Response<Body> {
+    if error.server_fault() {
+        log::warn!("{}", error);
+    } else {
+        log::debug!("{}", error);
+    }
+    error.to_response(request_id.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Method;
+    use hyper::body::to_bytes;
+    use super::super::super::api_http_server::routing::PathParams;
+
+    fn people_table() -> SqlTableSchema {
+        SqlTableSchema::new("people", vec![("name".to_string(), SQLType::Text), ("age".to_string(), SQLType::Integer)])
+    }
+
+    // an in-memory database with a people table
+    fn interface(table: &SqlTableSchema) -> SQLite3Interface {
+        let config = HashMap::from([("database_path".to_string(), ":memory:".to_string())]);
+        let (interface, _) = SQLite3Interface::connect(&config);
+        interface.create_tables_from_schemas(vec![table]);
+        interface
+    }
+
+    // a request with the parameters of the route it matched
+    fn request(method: Method, uri: &str, params: &[(&str, &str)], body: &str) -> Request<Body> {
+        let mut request = Request::builder().method(method).uri(uri).body(Body::from(body.to_string())).unwrap();
+        let params = params.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        request.extensions_mut().insert(PathParams(params));
+        request
+    }
+
+    async fn send(interface: &SQLite3Interface, table: &SqlTableSchema, mut request: Request<Body>) -> Response<Body> {
+        interface.process_api_request(&mut request, table).await
+    }
+
+    async fn body(response: Response<Body>) -> json::JsonValue {
+        let bytes = to_bytes(response.into_body()).await.unwrap();
+        json::parse(std::str::from_utf8(&bytes).unwrap()).unwrap()
+    }
+
+    async fn insert_people(interface: &SQLite3Interface, table: &SqlTableSchema) {
+        let inserted = request(
+            Method::POST,
+            "/people",
+            &[],
+            r#"{"rows": [{"name": "a", "age": 1}, {"name": "b", "age": 1}, {"name": "c", "age": 2}]}"#
+        );
+        assert_eq!(send(interface, table, inserted).await.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn returns_the_number_of_affected_rows() {
+        let table = people_table();
+        let interface = interface(&table);
+        insert_people(&interface, &table).await;
+
+        let updated = request(Method::PATCH, "/people", &[], r#"{"columns": {"age": 3}, "filters": {"age": 1}}"#);
+        let response = send(&interface, &table, updated).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, json::object!{affected: 2});
+
+        let deleted = request(Method::DELETE, "/people?age=3&name=a", &[], "");
+        assert_eq!(body(send(&interface, &table, deleted).await).await, json::object!{affected: 1});
+
+        // nothing matches, rather than the rows changed by an earlier statement
+        let deleted = request(Method::DELETE, "/people?age=3&name=a", &[], "");
+        assert_eq!(body(send(&interface, &table, deleted).await).await, json::object!{affected: 0});
+
+        let missing = request(Method::PATCH, "/people/9", &[("id", "9")], r#"{"columns": {"age": 4}}"#);
+        assert_eq!(send(&interface, &table, missing).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn returns_affected_rows_when_preferred() {
+        let table = people_table();
+        let interface = interface(&table);
+        insert_people(&interface, &table).await;
+
+        let mut updated = request(Method::PATCH, "/people?format=object", &[], r#"{"columns": {"age": 3}, "filters": {"age": 2}}"#);
+        updated.headers_mut().insert("Prefer", HeaderValue::from_static("return=representation"));
+        let response = send(&interface, &table, updated).await;
+        assert_eq!(response.headers()["Preference-Applied"], "return=representation");
+        assert_eq!(body(response).await, json::array![{id: 3, name: "c", age: 3}]);
+
+        let mut deleted = request(Method::DELETE, "/people?age=1", &[], "");
+        deleted.headers_mut().insert("Prefer", HeaderValue::from_static("return=minimal"));
+        let response = send(&interface, &table, deleted).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(to_bytes(response.into_body()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn selects_fields_in_the_given_order() {
+        let table = people_table();
+        let interface = interface(&table);
+        insert_people(&interface, &table).await;
+
+        let selected = request(Method::GET, "/people?fields=age,NAME&age=1", &[], "");
+        assert_eq!(body(send(&interface, &table, selected).await).await, json::array![[1, "a"], [1, "b"]]);
+
+        let invalid = request(Method::GET, "/people?fields=name,email", &[], "");
+        assert_eq!(send(&interface, &table, invalid).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn pages_without_the_id_field_have_a_cursor() {
+        let table = people_table();
+        let interface = interface(&table);
+        insert_people(&interface, &table).await;
+
+        let first = request(Method::GET, "/people?fields=name&limit=2", &[], "");
+        let response = send(&interface, &table, first).await;
+        let link = response.headers()["Link"].to_str().unwrap().to_string();
+        assert_eq!(link, "</people?fields=name&limit=2&cursor=0000000000000002>; rel=\"next\"");
+        assert_eq!(body(response).await, json::array![["a"], ["b"]]);
+
+        let next_uri = link.trim_start_matches('<').split_once('>').unwrap().0;
+        let next = request(Method::GET, next_uri, &[], "");
+        let response = send(&interface, &table, next).await;
+        assert!(!response.headers().contains_key("Link"));
+        assert_eq!(body(response).await, json::array![["c"]]);
+    }
+
+    #[tokio::test]
+    async fn returns_rows_in_the_requested_format() {
+        let table = people_table();
+        let interface = interface(&table);
+        insert_people(&interface, &table).await;
+
+        let objects = request(Method::GET, "/people?format=object&age=2", &[], "");
+        assert_eq!(body(send(&interface, &table, objects).await).await, json::array![{id: 3, name: "c", age: 2}]);
+
+        let arrays = request(Method::GET, "/people?format=Array&age=2", &[], "");
+        assert_eq!(body(send(&interface, &table, arrays).await).await, json::array![[3, "c", 2]]);
+
+        // the default format is used without a format arg
+        let record = request(Method::GET, "/people/1", &[("id", "1")], "");
+        assert_eq!(body(send(&interface, &table, record).await).await, json::array![1, "a", 1]);
+        let record = request(Method::GET, "/people/1?format=object", &[("id", "1")], "");
+        assert_eq!(body(send(&interface, &table, record).await).await, json::object!{id: 1, name: "a", age: 1});
+
+        let invalid = request(Method::GET, "/people?format=xml", &[], "");
+        assert_eq!(send(&interface, &table, invalid).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn returns_the_location_of_a_created_record() {
+        let table = people_table();
+        let interface = interface(&table);
+
+        let created = request(Method::POST, "/people/?format=object", &[], r#"{"columns": {"name": "a", "age": 1}}"#);
+        let response = send(&interface, &table, created).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["Location"], "/people/1");
+        assert_eq!(body(response).await, json::array![{id: 1, name: "a", age: 1}]);
+
+        // a batch has no single location
+        let created = request(Method::POST, "/people", &[], r#"{"rows": [{"name": "b", "age": 2}, {"name": "c", "age": 3}]}"#);
+        let response = send(&interface, &table, created).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(!response.headers().contains_key("Location"));
+
+        // an upserted record may not be new
+        let upserted = request(Method::POST, "/people?on_conflict=id", &[], r#"{"columns": {"id": 1, "name": "a", "age": 4}}"#);
+        let response = send(&interface, &table, upserted).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key("Location"));
+    }
+
+    #[tokio::test]
+    async fn maps_foreign_key_violations_to_error_codes() {
+        let companies = SqlTableSchema::new("companies", vec![("name".to_string(), SQLType::Text)]);
+        let mut people = people_table();
+        people.fields.push(("company_id".to_string(), SQLType::Integer));
+        people.field_options.insert(
+            "company_id".to_string(),
+            FieldOptions {references: Some("companies".to_string()), ..FieldOptions::default()}
+        );
+        let interface = interface(&companies);
+        interface.create_tables_from_schemas(vec![&people]);
+
+        let created = request(Method::POST, "/companies", &[], r#"{"columns": {"name": "x"}}"#);
+        assert_eq!(send(&interface, &companies, created).await.status(), StatusCode::CREATED);
+
+        let missing = request(Method::POST, "/people", &[], r#"{"columns": {"name": "a", "age": 1, "company_id": 2}}"#);
+        let response = send(&interface, &people, missing).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body(response).await,
+            json::object!{error: {code: "invalid_reference", message: "Referenced record doesn't exist", field: "company_id"}}
+        );
+
+        let missing = request(
+            Method::POST,
+            "/people",
+            &[],
+            r#"[{"columns": {"name": "a", "age": 1, "company_id": 1}}, {"columns": {"name": "b", "age": 1, "company_id": 2}}]"#
+        );
+        let response = send(&interface, &people, missing).await;
+        assert_eq!(body(response).await["error"]["message"], "Row 1: Referenced record doesn't exist");
+
+        let created = request(Method::POST, "/people", &[], r#"{"columns": {"name": "a", "age": 1, "company_id": 1}}"#);
+        assert_eq!(send(&interface, &people, created).await.status(), StatusCode::CREATED);
+
+        let referenced = request(Method::DELETE, "/companies/1", &[("id", "1")], "");
+        let response = send(&interface, &companies, referenced).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(body(response).await["error"]["code"], "referenced_record");
+    }
+}
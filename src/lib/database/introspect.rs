@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+
+use sqlite3::Connection;
+use sqlite3::Result as SqlResult;
+use sqlite3::Value as SqlValue;
+use json::JsonValue;
+
+use super::table_schema::{SqlTableSchema, FieldOptions, Index, relate_tables};
+use super::interfaces::SQLType;
+use super::migration::{MIGRATIONS_TABLE, INDEXES_TABLE};
+use super::value::json_to_sql_value;
+use super::query::prepare_rows;
+
+// reads the tables of an existing database as {route: table schema}, the same as read_config
+// so the database can be served, or a config written for it, without writing the config by hand
+// each table is at '/{table}', and tables that can't be served are logged and skipped
+// checks of the whole table can't be in the config, so are logged and not read
+pub fn schemas_from_database(connection: &Connection) -> SqlResult<HashMap<String, SqlTableSchema>> {
+    let table_names = read_rows(
+        connection,
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' AND name NOT IN (?, ?) ORDER BY name",
+        &[SqlValue::String(MIGRATIONS_TABLE.to_string()), SqlValue::String(INDEXES_TABLE.to_string())]
+    )?;
+
+    let mut tables = Vec::new();
+    for row in table_names {
+        if let [SqlValue::String(name)] = row.as_slice() {
+            if let Some(schema) = read_table(connection, name)? {
+                tables.push(schema);
+            }
+        }
+    }
+
+    // references to skipped tables can't be followed
+    let table_names: Vec<String> = tables.iter().map(|schema| schema.name.clone()).collect();
+    for schema in tables.iter_mut() {
+        for options in schema.field_options.values_mut() {
+            if options.references.as_ref().is_some_and(|table| !table_names.contains(table)) {
+                log::warn!("Reference of table '{}' to a skipped table isn't used", schema.name);
+                options.references = None;
+                options.on_delete = None;
+            }
+        }
+    }
+
+    Ok(relate_tables(&tables)
+        .into_iter()
+        .map(|schema| (format!("/{}", schema.name), schema))
+        .collect())
+}
+
+// a table's schema, or None if the table can't be served
+fn read_table(connection: &Connection, table_name: &str) -> SqlResult<Option<SqlTableSchema>> {
+    // names are used in SQL without quotes
+    if !is_name(table_name) {
+        log::warn!("Skipping table with a name that isn't valid in the config: {}", table_name);
+        return Ok(None)
+    }
+    let table = [SqlValue::String(table_name.to_string())];
+
+    let columns = read_rows(
+        connection,
+        "SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?) ORDER BY cid",
+        &table
+    )?;
+
+    // the api identifies records by an integer id primary key
+    let primary_key: Vec<&Vec<SqlValue>> = columns.iter()
+        .filter(|column| !matches!(column.get(4), Some(SqlValue::Integer(0))))
+        .collect();
+    let has_id = match primary_key.as_slice() {
+        [column] => matches!(
+            (&column[0], &column[1]),
+            (SqlValue::String(name), SqlValue::String(column_type))
+                if name.eq_ignore_ascii_case("id") && column_type.eq_ignore_ascii_case("integer")
+        ),
+        _ => false
+    };
+    if !has_id {
+        log::warn!("Skipping table without an 'id INTEGER PRIMARY KEY': {}", table_name);
+        return Ok(None)
+    }
+
+    let mut clauses = table_clauses(connection, table_name)?;
+    // the config only has checks of fields
+    for check in &clauses.checks {
+        log::warn!("Check of table {} isn't a check of a field, so isn't used: {}", table_name, check);
+    }
+
+    let mut fields = Vec::new();
+    let mut field_options = HashMap::new();
+    for column in columns.iter().filter(|column| matches!(column.get(4), Some(SqlValue::Integer(0)))) {
+        let (name, declared_type, not_null, default) = match column.as_slice() {
+            [SqlValue::String(name), SqlValue::String(declared_type), SqlValue::Integer(not_null), default, _] => {
+                (name.to_ascii_lowercase(), declared_type, *not_null != 0, default)
+            },
+            _ => continue
+        };
+        if !is_name(&name) {
+            log::warn!("Skipping table with a column name that isn't valid in the config: {}.{}", table_name, name);
+            return Ok(None)
+        }
+
+        let sql_type = sql_type_from_declared(declared_type);
+        let default = match default {
+            SqlValue::String(default) => {
+                let value = parse_default(&name, &sql_type, default);
+                if value.is_none() {
+                    log::warn!("Default of {}.{} isn't a value, so isn't used: {}", table_name, name, default);
+                }
+                value
+            },
+            _ => None
+        };
+
+        let ColumnClauses {check, collate} = clauses.columns.remove(&name).unwrap_or_default();
+        // collations in the config are names, which are used in SQL without quotes
+        let collate = collate.filter(|collate| {
+            if !is_name(collate) {
+                log::warn!("Collation of {}.{} isn't a name, so isn't used: {}", table_name, name, collate);
+            }
+            is_name(collate)
+        }).map(|collate| collate.to_ascii_uppercase());
+
+        let options = FieldOptions {not_null, default, check, collate, ..FieldOptions::default()};
+        if options.not_null || options.default.is_some() || options.check.is_some() || options.collate.is_some() {
+            field_options.insert(name.clone(), options);
+        }
+        fields.push((name, sql_type));
+    }
+
+    let foreign_keys = read_rows(
+        connection,
+        "SELECT \"from\", \"table\", \"to\", on_delete FROM pragma_foreign_key_list(?)",
+        &table
+    )?;
+    for foreign_key in foreign_keys {
+        if let [SqlValue::String(field), SqlValue::String(referenced), to, SqlValue::String(on_delete)] = foreign_key.as_slice() {
+            let field = field.to_ascii_lowercase();
+            // only references to the id of a table are supported, which is the primary key if 'to' is NULL
+            let to_id = match to {
+                SqlValue::String(to) => to.eq_ignore_ascii_case("id"),
+                _ => true
+            };
+            if !to_id || !matches!(fields.iter().find(|(name, _)| *name == field), Some((_, SQLType::Integer))) {
+                log::warn!("Reference of {}.{} isn't to an id, so isn't used", table_name, field);
+                continue
+            }
+
+            let options = field_options.entry(field).or_insert_with(FieldOptions::default);
+            options.references = Some(referenced.to_string());
+            options.on_delete = Some(on_delete.to_ascii_uppercase()).filter(|action| action != "NO ACTION");
+        }
+    }
+
+    let (unique, indexes) = read_indexes(connection, table_name)?;
+
+    let mut table = SqlTableSchema::new(table_name, fields);
+    table.field_options = field_options;
+    table.unique = unique;
+    table.indexes = indexes;
+    Ok(Some(table))
+}
+
+// (unique fields, indexes from a config) of a table
+// single column unique indexes are unique fields, so can be upsert keys
+// other indexes are only read if they are named as indexes from a config, '{table}_{name}_idx', as others are kept by the database
+fn read_indexes(connection: &Connection, table_name: &str) -> SqlResult<(Vec<String>, Vec<Index>)> {
+    let index_list = read_rows(
+        connection,
+        "SELECT name, \"unique\", partial FROM pragma_index_list(?) WHERE origin != 'pk'",
+        &[SqlValue::String(table_name.to_string())]
+    )?;
+
+    let mut unique = Vec::new();
+    let mut indexes = Vec::new();
+    for index in index_list {
+        let (name, is_unique, partial) = match index.as_slice() {
+            [SqlValue::String(name), SqlValue::Integer(is_unique), SqlValue::Integer(partial)] => (name, *is_unique != 0, *partial != 0),
+            _ => continue
+        };
+
+        // expressions in an index have no column name, so the index can't be in a config
+        let mut fields = Vec::new();
+        let mut expression = false;
+        for column in read_rows(connection, "SELECT name FROM pragma_index_info(?) ORDER BY seqno", &[SqlValue::String(name.clone())])? {
+            match column.as_slice() {
+                [SqlValue::String(field)] => fields.push(field.to_ascii_lowercase()),
+                _ => expression = true
+            }
+        }
+        if fields.is_empty() || expression {
+            continue
+        }
+
+        let from_config = name.starts_with(&format!("{}_", table_name)) && name.ends_with("_idx");
+        if from_config {
+            let sql = read_rows(connection, "SELECT sql FROM sqlite_master WHERE type = 'index' AND name = ?", &[SqlValue::String(name.clone())])?;
+            let condition = match sql.first().map(|row| row.as_slice()) {
+                Some([SqlValue::String(sql)]) if partial => sql.to_ascii_uppercase()
+                    .find(" WHERE ")
+                    .map(|start| sql[start + " WHERE ".len()..].trim().to_string()),
+                _ => None
+            };
+            indexes.push(Index {name: name.clone(), fields, unique: is_unique, condition});
+        } else if let [field] = fields.as_slice() {
+            if is_unique && !partial && field != "id" && !unique.contains(field) {
+                unique.push(field.clone());
+            }
+        }
+    }
+
+    Ok((unique, indexes))
+}
+
+// CHECK and COLLATE clauses of a column, which pragmas don't give
+#[derive(Debug, Default, PartialEq)]
+pub struct ColumnClauses {
+    // SQL expression, without the parentheses
+    pub check: Option<String>,
+    pub collate: Option<String>,
+}
+
+// clauses of the columns of a table, by lowercase column name, and CHECK constraints of the whole table
+#[derive(Debug, Default)]
+pub struct TableClauses {
+    pub columns: HashMap<String, ColumnClauses>,
+    pub checks: Vec<String>,
+}
+
+// clauses of a table, read from the CREATE TABLE statement stored by the database
+pub fn table_clauses(connection: &Connection, table_name: &str) -> SqlResult<TableClauses> {
+    let sql = read_rows(
+        connection,
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?",
+        &[SqlValue::String(table_name.to_string())]
+    )?;
+
+    Ok(match sql.first().map(|row| row.as_slice()) {
+        Some([SqlValue::String(sql)]) => parse_table_clauses(sql),
+        _ => TableClauses::default()
+    })
+}
+
+pub fn parse_table_clauses(sql: &str) -> TableClauses {
+    let mut clauses = TableClauses::default();
+
+    // the column definitions and table constraints are in the first parentheses
+    let body = sql_tokens(sql).into_iter().find(|token| token.starts_with('('));
+    let body = match body {
+        Some(body) => &body[1..body.len() - 1],
+        None => return clauses
+    };
+
+    let tokens = sql_tokens(body);
+    for definition in tokens.split(|token| *token == ",") {
+        let first = match definition.first() {
+            Some(first) => first.to_ascii_uppercase(),
+            None => continue
+        };
+        // CHECK expressions follow the keyword in parentheses
+        let checks = definition.windows(2)
+            .filter(|pair| pair[0].eq_ignore_ascii_case("CHECK") && pair[1].starts_with('('))
+            .map(|pair| pair[1][1..pair[1].len() - 1].trim().to_string());
+
+        match first.as_str() {
+            "CONSTRAINT" | "PRIMARY" | "UNIQUE" | "CHECK" | "FOREIGN" => clauses.checks.extend(checks),
+            _ => {
+                let checks: Vec<String> = checks.collect();
+                let check = match checks.as_slice() {
+                    [] => None,
+                    [check] => Some(check.clone()),
+                    _ => Some(checks.iter().map(|check| format!("({})", check)).collect::<Vec<String>>().join(" AND "))
+                };
+                let collate = definition.windows(2)
+                    .find(|pair| pair[0].eq_ignore_ascii_case("COLLATE"))
+                    .map(|pair| unquote(pair[1]).to_string());
+
+                clauses.columns.insert(unquote(definition[0]).to_ascii_lowercase(), ColumnClauses {check, collate});
+            }
+        }
+    }
+
+    clauses
+}
+
+// splits SQL into words, quoted names and strings, parenthesised groups and other characters
+// whitespace and comments are skipped
+fn sql_tokens(sql: &str) -> Vec<&str> {
+    let bytes = sql.as_bytes();
+    let mut tokens = Vec::new();
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let end = token_end(bytes, i);
+        let token = &sql[i..end];
+        if !token.trim().is_empty() && !token.starts_with("--") && !token.starts_with("/*") {
+            tokens.push(token);
+        }
+        i = end;
+    }
+    tokens
+}
+
+// the index after the token starting at start
+fn token_end(bytes: &[u8], start: usize) -> usize {
+    let is_word = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'$' || !byte.is_ascii();
+    let find = |from: usize, pattern: &[u8]| bytes[from..]
+        .windows(pattern.len())
+        .position(|window| window == pattern)
+        .map_or(bytes.len(), |position| from + position + pattern.len());
+
+    match bytes[start] {
+        b'-' if bytes.get(start + 1) == Some(&b'-') => find(start, b"\n"),
+        b'/' if bytes.get(start + 1) == Some(&b'*') => find(start + 2, b"*/"),
+        b'[' => find(start, b"]"),
+        quote @ (b'\'' | b'"' | b'`') => {
+            // a doubled quote is part of the name or string
+            let mut end = start + 1;
+            while end < bytes.len() {
+                if bytes[end] == quote {
+                    if bytes.get(end + 1) != Some(&quote) {
+                        return end + 1
+                    }
+                    end += 1;
+                }
+                end += 1;
+            }
+            bytes.len()
+        },
+        b'(' => {
+            let mut end = start + 1;
+            while end < bytes.len() && bytes[end] != b')' {
+                end = token_end(bytes, end);
+            }
+            (end + 1).min(bytes.len())
+        },
+        byte if is_word(byte) => {
+            let mut end = start + 1;
+            while end < bytes.len() && is_word(bytes[end]) {
+                end += 1;
+            }
+            end
+        },
+        _ => start + 1
+    }
+}
+
+// a name without the quotes around it
+fn unquote(name: &str) -> &str {
+    let quoted = ["\"\"", "``", "[]", "''"].iter().any(|quotes| {
+        let (open, close) = quotes.split_at(1);
+        name.len() >= 2 && name.starts_with(open) && name.ends_with(close)
+    });
+    match quoted {
+        true => &name[1..name.len() - 1],
+        false => name
+    }
+}
+
+// the type of a column, from its declared type
+// types the api doesn't create are converted using SQLite's rules for the affinity of declared types
+pub fn sql_type_from_declared(declared: &str) -> SQLType {
+    let declared = declared.to_ascii_uppercase();
+    match declared.as_str() {
+        "NULL" => SQLType::Null,
+        "BOOLEAN" | "BOOL" => SQLType::Boolean,
+        // without TEXT, from older versions of the api or other programs
+        "DATE TEXT" | "DATE" => SQLType::Date,
+        "DATETIME TEXT" | "DATETIME" | "TIMESTAMP" => SQLType::DateTime,
+        "JSON TEXT" | "JSON" => SQLType::Json,
+        // columns without a type can have values of any type, and are most often text
+        "" => SQLType::Text,
+        _ => match affinity(&declared) {
+            "INTEGER" => SQLType::Integer,
+            "TEXT" => SQLType::Text,
+            "BLOB" => SQLType::Blob,
+            // real and numeric affinity
+            _ => SQLType::Real
+        }
+    }
+}
+
+// the affinity of a declared type, which decides how SQLite stores values in the column
+// https://www.sqlite.org/datatype3.html#determination_of_column_affinity
+pub fn affinity(declared: &str) -> &'static str {
+    let declared = declared.to_ascii_uppercase();
+    match declared.as_str() {
+        _ if declared.contains("INT") => "INTEGER",
+        _ if ["CHAR", "CLOB", "TEXT"].iter().any(|name| declared.contains(name)) => "TEXT",
+        _ if declared.contains("BLOB") || declared.is_empty() => "BLOB",
+        _ if ["REAL", "FLOA", "DOUB"].iter().any(|name| declared.contains(name)) => "REAL",
+        _ => "NUMERIC"
+    }
+}
+
+// a default from the database, which is SQL, as a value of the field's type
+// None for expressions, such as CURRENT_TIMESTAMP, as the config's defaults are values
+pub fn parse_default(field: &str, sql_type: &SQLType, default: &str) -> Option<SqlValue> {
+    let default = default.trim();
+
+    // blobs are X'hex', and are base64 in json
+    let blob = default.strip_prefix("X'")
+        .or_else(|| default.strip_prefix("x'"))
+        .and_then(|hex| hex.strip_suffix('\''))
+        .filter(|hex| hex.len() % 2 == 0)
+        .and_then(|hex| (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect::<Option<Vec<u8>>>());
+    if let Some(blob) = blob {
+        return json_to_sql_value(field, sql_type, &JsonValue::from(base64::encode(blob))).ok()
+    }
+
+    let value = match default.strip_prefix('\'').and_then(|quoted| quoted.strip_suffix('\'')) {
+        // json is stored as json text, rather than as a json string
+        Some(quoted) if matches!(sql_type, SQLType::Json) => return Some(SqlValue::String(quoted.replace("''", "'"))),
+        Some(quoted) => JsonValue::from(quoted.replace("''", "'")),
+        None if default.eq_ignore_ascii_case("null") => return None,
+        None if default.eq_ignore_ascii_case("true") => JsonValue::from(true),
+        None if default.eq_ignore_ascii_case("false") => JsonValue::from(false),
+        None => match (default.parse::<i64>(), default.parse::<f64>()) {
+            (Ok(integer), _) => JsonValue::from(integer),
+            (_, Ok(float)) => JsonValue::from(float),
+            _ => return None
+        }
+    };
+
+    json_to_sql_value(field, sql_type, &value).ok()
+}
+
+// a name that can be used in SQL without quotes
+fn is_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// values of every row of a statement
+fn read_rows(connection: &Connection, sql: &str, bindings: &[SqlValue]) -> SqlResult<Vec<Vec<SqlValue>>> {
+    let mut selected = prepare_rows(connection, sql.to_string(), bindings)?;
+    let mut rows = Vec::new();
+    while let Some(row) = selected.next_row()? {
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_checks_and_collations_of_columns() {
+        let clauses = parse_table_clauses(
+            "CREATE TABLE items (ID INTEGER PRIMARY KEY, qty INTEGER NOT NULL CHECK (qty >= 0 AND qty < max(1, 2)), \
+                \"Label\" TEXT DEFAULT 'a, b)' COLLATE NOCASE, price REAL)"
+        );
+
+        assert_eq!(clauses.columns["qty"], ColumnClauses {check: Some("qty >= 0 AND qty < max(1, 2)".to_string()), collate: None});
+        assert_eq!(clauses.columns["label"], ColumnClauses {check: None, collate: Some("NOCASE".to_string())});
+        assert_eq!(clauses.columns["price"], ColumnClauses::default());
+        assert!(clauses.checks.is_empty());
+    }
+
+    #[test]
+    fn reads_checks_and_collations_of_fields() {
+        let connection = sqlite3::open(":memory:").unwrap();
+        connection.execute(
+            "CREATE TABLE things (id INTEGER PRIMARY KEY, name VARCHAR(20) COLLATE nocase CHECK (name != ''), \
+                lo INT, hi INT, CHECK (lo <= hi));"
+        ).unwrap();
+
+        let tables = schemas_from_database(&connection).unwrap();
+        let things = &tables["/things"];
+        let name = things.field_options("name").unwrap();
+
+        assert_eq!(name.check.as_deref(), Some("name != ''"));
+        assert_eq!(name.collate.as_deref(), Some("NOCASE"));
+        assert!(things.field_options("lo").is_none());
+    }
+
+    #[test]
+    fn parses_checks_of_the_table() {
+        let clauses = parse_table_clauses(
+            "CREATE TABLE events (id INTEGER PRIMARY KEY, -- the start, (not a check\n\
+                start INTEGER CHECK(start > 0) CHECK (start < 10), /* ) */ finish INTEGER, \
+                CONSTRAINT ordered CHECK (start <= finish), UNIQUE (start, finish))"
+        );
+
+        assert_eq!(clauses.columns["start"].check.as_deref(), Some("(start > 0) AND (start < 10)"));
+        assert_eq!(clauses.checks, vec!["start <= finish"]);
+        assert_eq!(clauses.columns.len(), 3);
+    }
+}
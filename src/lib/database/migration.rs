@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+
+use sqlite3::Connection;
+use sqlite3::Result as SqlResult;
+use sqlite3::Value as SqlValue;
+use sqlite3::Error as SqlError;
+
+use super::table_schema::{SqlTableSchema, FieldOptions};
+use super::interfaces::{SQLType, table_sql, column_definition, column_type};
+use super::introspect::{sql_type_from_declared, affinity, parse_default, table_clauses, ColumnClauses};
+use super::query::prepare_rows;
+
+// table recording the migrations applied to the database
+pub const MIGRATIONS_TABLE: &str = "_schema_migrations";
+// table recording the indexes created from the config, so indexes created by others are never dropped
+pub const INDEXES_TABLE: &str = "_schema_indexes";
+
+// a column of a table in the database, other than the id
+pub struct ExistingColumn {
+    // lowercase, as fields in the config are
+    pub name: String,
+    // declared type, uppercase
+    pub column_type: String,
+    pub not_null: bool,
+    // SQL of the default, e.g. 'abc' or CURRENT_TIMESTAMP
+    pub default: Option<String>,
+    pub check: Option<String>,
+    pub collate: Option<String>,
+    // referenced table, and the action when a referenced record is deleted, other than NO ACTION
+    pub references: Option<(String, Option<String>)>,
+}
+
+// differences between a table in the config and the same table in the database
+pub struct TableChanges {
+    // fields of the config that aren't columns in the database
+    pub added: Vec<String>,
+    // (field, type in the database, type in the config)
+    pub changed: Vec<(String, String, String)>,
+    // (field, options of the config that are different in the database), e.g. nullable or check
+    pub constrained: Vec<(String, Vec<&'static str>)>,
+    // columns in the database that aren't fields of the config
+    pub dropped: Vec<String>,
+}
+
+impl TableChanges {
+    pub fn compare(schema: &SqlTableSchema, existing: &[ExistingColumn]) -> Self {
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+        let mut constrained = Vec::new();
+        for (field, sql_type) in &schema.fields {
+            let column = existing.iter().find(|column| column.name == *field);
+            if column.is_none() {
+                added.push(field.clone());
+                continue
+            }
+            let column = column.unwrap();
+
+            // columns from other programs can have other names for the field's type, e.g. VARCHAR(100) for TEXT
+            // but must have the same affinity, or values may be stored as another type
+            if column_type(&sql_type_from_declared(&column.column_type)) != column_type(sql_type)
+                || affinity(&column.column_type) != affinity(column_type(sql_type)) {
+                changed.push((field.clone(), column.column_type.clone(), column_type(sql_type).to_string()));
+            }
+
+            let options = changed_options(field, sql_type, schema.field_options(field).cloned().unwrap_or_default(), column);
+            if !options.is_empty() {
+                constrained.push((field.clone(), options));
+            }
+        }
+
+        let dropped = existing.iter()
+            .filter(|column| !schema.fields.iter().any(|(field, _)| *field == column.name))
+            .map(|column| column.name.clone())
+            .collect();
+
+        Self {added, changed, constrained, dropped}
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.constrained.is_empty() && self.dropped.is_empty()
+    }
+
+    // changes that ALTER TABLE can't make, so the table must be rebuilt
+    // columns are added without a rebuild unless they can't have NULL for existing rows
+    pub fn needs_rebuild(&self, schema: &SqlTableSchema) -> bool {
+        !self.changed.is_empty()
+            || !self.constrained.is_empty()
+            || !self.dropped.is_empty()
+            || self.added.iter().any(|field| !can_add_column(schema, field))
+    }
+
+    pub fn describe(&self) -> String {
+        let added = self.added.iter().map(|field| format!("added column {}", field));
+        let changed = self.changed.iter().map(|(field, from, to)| format!("changed type of {} from {} to {}", field, from, to));
+        let constrained = self.constrained.iter().map(|(field, options)| format!("changed {} of {}", options.join(", "), field));
+        let dropped = self.dropped.iter().map(|field| format!("dropped column {}", field));
+
+        added.chain(changed).chain(constrained).chain(dropped).collect::<Vec<String>>().join(", ")
+    }
+}
+
+// names of the options of a field that are different to its column's constraints
+fn changed_options(field: &str, sql_type: &SQLType, options: FieldOptions, column: &ExistingColumn) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+
+    if options.not_null != column.not_null {
+        changed.push("nullable");
+    }
+    // defaults are compared as values, e.g. 1 and 1.0 are the same default of a real field
+    // defaults that are expressions can't be in the config, so are the same as no default
+    let default = column.default.as_ref().and_then(|default| parse_default(field, sql_type, default));
+    if options.default != default {
+        changed.push("default");
+    }
+    if options.check.as_deref().map(str::trim) != column.check.as_deref() {
+        changed.push("check");
+    }
+    let (references, on_delete) = match &column.references {
+        Some((table, on_delete)) => (Some(table.as_str()), on_delete.as_deref()),
+        None => (None, None)
+    };
+    if options.references.as_deref().map(str::to_ascii_lowercase).as_deref() != references {
+        changed.push("references");
+    }
+    if options.on_delete.as_deref().filter(|action| *action != "NO ACTION") != on_delete {
+        changed.push("on_delete");
+    }
+    // BINARY is the collation of columns without one
+    let collation = |collate: Option<&str>| collate.unwrap_or("BINARY").to_ascii_uppercase();
+    if collation(options.collate.as_deref()) != collation(column.collate.as_deref()) {
+        changed.push("collate");
+    }
+
+    changed
+}
+
+// columns of a table in the database, or None if the table doesn't exist
+pub fn existing_columns(connection: &Connection, table_name: &str) -> SqlResult<Option<Vec<ExistingColumn>>> {
+    let table = [SqlValue::String(table_name.to_string())];
+    let mut selected = prepare_rows(
+        connection,
+        "SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?)".to_string(),
+        &table
+    )?;
+
+    let mut rows = Vec::new();
+    while let Some(row) = selected.next_row()? {
+        rows.push(row);
+    }
+    if rows.is_empty() {
+        return Ok(None)
+    }
+
+    let mut foreign_keys = prepare_rows(
+        connection,
+        "SELECT \"from\", \"table\", on_delete FROM pragma_foreign_key_list(?)".to_string(),
+        &table
+    )?;
+    // column: (referenced table, on delete)
+    let mut references = HashMap::new();
+    while let Some(row) = foreign_keys.next_row()? {
+        if let [SqlValue::String(field), SqlValue::String(referenced), SqlValue::String(on_delete)] = row.as_slice() {
+            let on_delete = Some(on_delete.to_ascii_uppercase()).filter(|action| action != "NO ACTION");
+            references.insert(field.to_ascii_lowercase(), (referenced.to_ascii_lowercase(), on_delete));
+        }
+    }
+
+    let mut clauses = table_clauses(connection, table_name)?;
+
+    let mut columns = Vec::new();
+    for row in rows {
+        // the id primary key isn't a field
+        if let [SqlValue::String(name), SqlValue::String(column_type), SqlValue::Integer(not_null), default, SqlValue::Integer(0)] = row.as_slice() {
+            let name = name.to_ascii_lowercase();
+            let ColumnClauses {check, collate} = clauses.columns.remove(&name).unwrap_or_default();
+            columns.push(ExistingColumn {
+                column_type: column_type.to_ascii_uppercase(),
+                not_null: *not_null != 0,
+                default: match default {
+                    SqlValue::String(default) => Some(default.clone()),
+                    _ => None
+                },
+                check,
+                collate,
+                references: references.remove(&name),
+                name,
+            });
+        }
+    }
+
+    Ok(Some(columns))
+}
+
+// a column can be added to a table with existing rows if it can be NULL, or has a default
+// referencing columns with foreign keys enabled can only be added with a NULL default
+pub fn can_add_column(schema: &SqlTableSchema, field: &str) -> bool {
+    match schema.field_options(field) {
+        Some(options) => {
+            let needs_value = options.not_null && options.default.is_none();
+            let references_default = options.references.is_some() && options.default.is_some();
+            !needs_value && !references_default
+        },
+        None => true
+    }
+}
+
+pub fn add_column(connection: &Connection, schema: &SqlTableSchema, field: &str) -> SqlResult<()> {
+    let sql_type = schema.field_type(field)
+        .ok_or_else(|| SqlError {code: None, message: Some(format!("Field {} isn't in the table", field))})?;
+    let sql = format!(
+        "ALTER TABLE {} ADD COLUMN {};",
+        schema.name,
+        column_definition(field, sql_type, schema.field_options(field))
+    );
+
+    log::info!("Adding column with SQL: {}", sql);
+    connection.execute(sql)
+}
+
+// replaces the table with a new table created from the config, copying the columns in both
+// the values of changed columns are copied as they are, and dropped columns are lost
+// indexes of the table are dropped with it, so must be created again
+pub fn rebuild_table(connection: &Connection, schema: &SqlTableSchema, existing: &[ExistingColumn]) -> SqlResult<()> {
+    let new_table = format!("{}_rebuild", schema.name);
+    let copied: Vec<&str> = std::iter::once("id")
+        .chain(schema.fields.iter()
+            .map(|(field, _)| field.as_str())
+            .filter(|field| existing.iter().any(|column| column.name == *field)))
+        .collect();
+
+    // foreign keys can't be disabled during a transaction
+    // they are disabled so referencing tables aren't changed when the table is dropped
+    connection.execute("PRAGMA foreign_keys = OFF")?;
+    connection.execute("BEGIN")?;
+
+    let rebuilt = (|| {
+        let invalid_before = invalid_references(connection, &schema.name)?;
+        connection.execute(table_sql(&new_table, &schema.fields, &schema.field_options))?;
+        connection.execute(format!(
+            "INSERT INTO {} ({}) SELECT {} FROM {};",
+            new_table, copied.join(", "), copied.join(", "), schema.name
+        ))?;
+        connection.execute(format!("DROP TABLE {};", schema.name))?;
+        connection.execute(format!("ALTER TABLE {} RENAME TO {};", new_table, schema.name))?;
+
+        // copied values must still reference existing records
+        // references that were invalid before the rebuild don't prevent it
+        let invalid = invalid_references(connection, &schema.name)?;
+        if invalid.iter().any(|reference| !invalid_before.contains(reference)) {
+            return Err(SqlError {code: None, message: Some("Rebuilt table has invalid references".to_string())})
+        }
+        connection.execute("COMMIT")
+    })();
+
+    if rebuilt.is_err() {
+        if let Err(rollback_error) = connection.execute("ROLLBACK") {
+            log::warn!("Error rolling back rebuild: {:?}", rollback_error.message);
+        }
+    }
+    connection.execute("PRAGMA foreign_keys = ON")?;
+    rebuilt
+}
+
+// (table, rowid, referenced table) of records of the table with references to records that don't exist
+// and of records of other tables with references to the table
+// other references aren't checked, so invalid references elsewhere don't prevent a rebuild
+fn invalid_references(connection: &Connection, table_name: &str) -> SqlResult<Vec<(String, i64, String)>> {
+    let mut referencing = prepare_rows(
+        connection,
+        "SELECT DISTINCT m.name FROM sqlite_master AS m, pragma_foreign_key_list(m.name) AS f \
+            WHERE m.type = 'table' AND m.name != ?1 AND lower(f.\"table\") = lower(?1)".to_string(),
+        &[SqlValue::String(table_name.to_string())]
+    )?;
+    let mut checked = vec![table_name.to_string()];
+    while let Some(row) = referencing.next_row()? {
+        if let [SqlValue::String(name)] = row.as_slice() {
+            checked.push(name.clone());
+        }
+    }
+
+    let mut invalid = Vec::new();
+    for table in checked {
+        // only references to the table are checked in other tables
+        let mut selected = prepare_rows(
+            connection,
+            "SELECT \"table\", rowid, parent FROM pragma_foreign_key_check(?1) WHERE ?1 = ?2 OR lower(parent) = lower(?2)".to_string(),
+            &[SqlValue::String(table), SqlValue::String(table_name.to_string())]
+        )?;
+        while let Some(row) = selected.next_row()? {
+            if let [SqlValue::String(table), SqlValue::Integer(rowid), SqlValue::String(parent)] = row.as_slice() {
+                invalid.push((table.clone(), *rowid, parent.clone()));
+            }
+        }
+    }
+    Ok(invalid)
+}
+
+pub fn create_migrations_table(connection: &Connection) -> SqlResult<()> {
+    connection.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, applied_at TEXT NOT NULL, description TEXT NOT NULL);",
+        MIGRATIONS_TABLE
+    ))
+}
+
+pub fn record_migration(connection: &Connection, description: &str) -> SqlResult<()> {
+    let mut cursor = connection
+        .prepare(format!(
+            "INSERT INTO {} (applied_at, description) VALUES (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'), ?);",
+            MIGRATIONS_TABLE
+        ))?
+        .cursor();
+    cursor.bind(&[SqlValue::String(description.to_string())])?;
+    cursor.next()?;
+    Ok(())
+}
+
+fn create_indexes_table(connection: &Connection) -> SqlResult<()> {
+    connection.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} (name TEXT PRIMARY KEY, table_name TEXT NOT NULL);",
+        INDEXES_TABLE
+    ))
+}
+
+// names of the indexes of a table that were created from the config
+pub fn created_indexes(connection: &Connection, table_name: &str) -> SqlResult<Vec<String>> {
+    create_indexes_table(connection)?;
+    let mut selected = prepare_rows(
+        connection,
+        format!("SELECT name FROM {} WHERE table_name = ? ORDER BY name", INDEXES_TABLE),
+        &[SqlValue::String(table_name.to_string())]
+    )?;
+
+    let mut names = Vec::new();
+    while let Some(row) = selected.next_row()? {
+        if let [SqlValue::String(name)] = row.as_slice() {
+            names.push(name.clone());
+        }
+    }
+    Ok(names)
+}
+
+pub fn record_index(connection: &Connection, table_name: &str, name: &str) -> SqlResult<()> {
+    create_indexes_table(connection)?;
+    prepare_rows(
+        connection,
+        format!("INSERT OR IGNORE INTO {} (name, table_name) VALUES (?, ?);", INDEXES_TABLE),
+        &[SqlValue::String(name.to_string()), SqlValue::String(table_name.to_string())]
+    )?.next_row()?;
+    Ok(())
+}
+
+// drops an index created from the config, which may already have been dropped with its table
+pub fn drop_created_index(connection: &Connection, name: &str) -> SqlResult<()> {
+    connection.execute(format!("DROP INDEX IF EXISTS {};", name))?;
+    prepare_rows(
+        connection,
+        format!("DELETE FROM {} WHERE name = ?;", INDEXES_TABLE),
+        &[SqlValue::String(name.to_string())]
+    )?.next_row()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema(name: &str, fields: &[(&str, SQLType, FieldOptions)]) -> SqlTableSchema {
+        let mut schema = SqlTableSchema::new(name, fields.iter().map(|(field, sql_type, _)| (field.to_string(), sql_type.clone())).collect());
+        schema.field_options = fields.iter().map(|(field, _, options)| (field.to_string(), options.clone())).collect();
+        schema
+    }
+
+    fn references(table: &str) -> FieldOptions {
+        FieldOptions {references: Some(table.to_string()), ..FieldOptions::default()}
+    }
+
+    fn create(connection: &Connection, schema: &SqlTableSchema) {
+        connection.execute(table_sql(&schema.name, &schema.fields, &schema.field_options)).unwrap();
+    }
+
+    fn changes(connection: &Connection, schema: &SqlTableSchema) -> TableChanges {
+        TableChanges::compare(schema, &existing_columns(connection, &schema.name).unwrap().unwrap())
+    }
+
+    #[test]
+    fn finds_no_changes_to_a_table_created_from_the_config() {
+        let connection = sqlite3::open(":memory:").unwrap();
+        let items = schema("items", &[
+            ("qty", SQLType::Integer, FieldOptions {
+                not_null: true,
+                default: Some(SqlValue::Integer(0)),
+                check: Some("qty >= 0".to_string()),
+                ..FieldOptions::default()
+            }),
+            ("price", SQLType::Real, FieldOptions {default: Some(SqlValue::Float(1.0)), ..FieldOptions::default()}),
+            ("label", SQLType::Text, FieldOptions {collate: Some("NOCASE".to_string()), ..FieldOptions::default()}),
+            ("meta", SQLType::Json, FieldOptions {default: Some(SqlValue::String("{\"a\":1}".to_string())), ..FieldOptions::default()}),
+        ]);
+        create(&connection, &items);
+
+        assert!(changes(&connection, &items).is_empty());
+    }
+
+    #[test]
+    fn finds_added_changed_and_dropped_columns() {
+        let connection = sqlite3::open(":memory:").unwrap();
+        create(&connection, &schema("people", &[
+            ("age", SQLType::Integer, FieldOptions::default()),
+            ("nickname", SQLType::Text, FieldOptions::default()),
+        ]));
+
+        let people = schema("people", &[
+            ("age", SQLType::Text, FieldOptions::default()),
+            ("name", SQLType::Text, FieldOptions::default()),
+        ]);
+        let changes = changes(&connection, &people);
+
+        assert_eq!(changes.added, vec!["name".to_string()]);
+        assert_eq!(changes.changed, vec![("age".to_string(), "INTEGER".to_string(), "TEXT".to_string())]);
+        assert_eq!(changes.dropped, vec!["nickname".to_string()]);
+        assert!(changes.needs_rebuild(&people));
+    }
+
+    #[test]
+    fn adds_columns_that_can_be_null_without_a_rebuild() {
+        let connection = sqlite3::open(":memory:").unwrap();
+        create(&connection, &schema("people", &[]));
+
+        let people = schema("people", &[
+            ("name", SQLType::Text, FieldOptions::default()),
+            ("age", SQLType::Integer, FieldOptions {not_null: true, ..FieldOptions::default()}),
+        ]);
+        let changes = changes(&connection, &people);
+
+        assert!(can_add_column(&people, "name"));
+        assert!(!can_add_column(&people, "age"));
+        assert!(changes.needs_rebuild(&people));
+    }
+
+    #[test]
+    fn finds_changed_constraints() {
+        let connection = sqlite3::open(":memory:").unwrap();
+        create(&connection, &schema("companies", &[]));
+        create(&connection, &schema("people", &[
+            ("age", SQLType::Integer, FieldOptions {check: Some("age >= 0".to_string()), ..FieldOptions::default()}),
+            ("company_id", SQLType::Integer, references("companies")),
+        ]));
+
+        let people = schema("people", &[
+            ("age", SQLType::Integer, FieldOptions {not_null: true, check: Some("age > 0".to_string()), ..FieldOptions::default()}),
+            ("company_id", SQLType::Integer, FieldOptions {on_delete: Some("CASCADE".to_string()), ..references("companies")}),
+        ]);
+        let changes = changes(&connection, &people);
+
+        assert_eq!(changes.constrained, vec![
+            ("age".to_string(), vec!["nullable", "check"]),
+            ("company_id".to_string(), vec!["on_delete"]),
+        ]);
+        assert!(changes.needs_rebuild(&people));
+    }
+
+    #[test]
+    fn rebuilds_with_invalid_references_in_other_tables() {
+        let connection = sqlite3::open(":memory:").unwrap();
+        let companies = schema("companies", &[("name", SQLType::Text, FieldOptions::default())]);
+        create(&connection, &companies);
+        create(&connection, &schema("people", &[("company_id", SQLType::Integer, references("companies"))]));
+        create(&connection, &schema("teams", &[("lead_id", SQLType::Integer, references("people"))]));
+        connection.execute("INSERT INTO companies (name) VALUES ('a'); INSERT INTO people (company_id) VALUES (1);").unwrap();
+        // an invalid reference to a table that isn't rebuilt
+        connection.execute("INSERT INTO teams (lead_id) VALUES (9);").unwrap();
+
+        let companies = schema("companies", &[("title", SQLType::Text, FieldOptions::default())]);
+        let existing = existing_columns(&connection, "companies").unwrap().unwrap();
+        rebuild_table(&connection, &companies, &existing).unwrap();
+
+        assert!(existing_columns(&connection, "companies").unwrap().unwrap().iter().any(|column| column.name == "title"));
+    }
+
+    #[test]
+    fn rolls_back_a_rebuild_that_adds_invalid_references() {
+        let connection = sqlite3::open(":memory:").unwrap();
+        create(&connection, &schema("companies", &[]));
+        create(&connection, &schema("people", &[("company_id", SQLType::Integer, FieldOptions::default())]));
+        connection.execute("INSERT INTO people (company_id) VALUES (9);").unwrap();
+
+        let people = schema("people", &[("company_id", SQLType::Integer, references("companies"))]);
+        let existing = existing_columns(&connection, "people").unwrap().unwrap();
+
+        assert!(rebuild_table(&connection, &people, &existing).is_err());
+        assert!(existing_columns(&connection, "people").unwrap().unwrap()[0].references.is_none());
+    }
+}
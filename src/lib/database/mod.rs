@@ -1,4 +1,11 @@
 pub mod interfaces;
 pub mod table_schema;
 pub mod query;
+pub mod filter;
+pub mod expand;
+pub mod pagination;
+pub mod migration;
+pub mod introspect;
+pub mod value;
+pub mod error;
 pub mod response;
\ No newline at end of file
@@ -0,0 +1,94 @@
+use super::super::api_http_server::routing::{split_uri_args, percent_decode};
+
+// query string parameters used for pagination, which can't be used as filters
+pub const PAGINATION_PARAMS: [&str; 3] = ["limit", "offset", "cursor"];
+
+// A cursor is an opaque token holding the id of the last row on a page
+pub fn encode_cursor(last_id: i64) -> String {
+    last_id.to_be_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_cursor(cursor: &str) -> Option<i64> {
+    // each byte is 2 hex digits, without signs, which from_str_radix would accept
+    if cursor.len() != 16 || !cursor.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None
+    }
+
+    let mut bytes = [0u8; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&cursor[i*2..i*2+2], 16).ok()?;
+    }
+    Some(i64::from_be_bytes(bytes))
+}
+
+// where the next page starts
+pub enum NextPage {
+    // after the row with this id
+    Cursor(i64),
+    // after this many rows, used when results aren't ordered by id
+    Offset(usize),
+}
+
+// the uri of the next page, keeping any other uri args
+pub fn next_page_uri(uri: String, limit: usize, next_page: NextPage) -> String {
+    let (base_uri, uri_args) = split_uri_args(uri);
+
+    let mut args: Vec<&str> = uri_args
+        .split('&')
+        .filter(|arg| {
+            let key = arg.split_once('=').map(|(key, _)| key).unwrap_or(arg);
+            !arg.is_empty() && !PAGINATION_PARAMS.contains(&percent_decode(key).to_lowercase().as_str())
+        })
+        .collect();
+
+    let limit_arg = format!("limit={}", limit);
+    let next_page_arg = match next_page {
+        NextPage::Cursor(last_id) => format!("cursor={}", encode_cursor(last_id)),
+        NextPage::Offset(offset) => format!("offset={}", offset),
+    };
+    args.push(&limit_arg);
+    args.push(&next_page_arg);
+
+    format!("{}?{}", base_uri, args.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_encoded_cursors() {
+        for id in [0, 1, 255, 4096, -1, i64::MAX, i64::MIN] {
+            assert_eq!(decode_cursor(&encode_cursor(id)), Some(id));
+        }
+        assert_eq!(encode_cursor(1), "0000000000000001");
+    }
+
+    #[test]
+    fn rejects_malformed_cursors() {
+        for cursor in ["", "1", "000000000000001", "00000000000000001", "000000000000000g", "+000000000000001", "00000000000000é"] {
+            assert_eq!(decode_cursor(cursor), None, "{}", cursor);
+        }
+    }
+
+    #[test]
+    fn next_page_keeps_other_args() {
+        assert_eq!(
+            next_page_uri("/people?name=john&limit=2&cursor=0000000000000001&age%5Bgt%5D=3".to_string(), 2, NextPage::Cursor(4)),
+            "/people?name=john&age%5Bgt%5D=3&limit=2&cursor=0000000000000004"
+        );
+        assert_eq!(next_page_uri("/people?limit=2".to_string(), 2, NextPage::Cursor(2)), "/people?limit=2&cursor=0000000000000002");
+    }
+
+    #[test]
+    fn next_page_switches_between_offset_and_cursor() {
+        assert_eq!(
+            next_page_uri("/people?order_by=name&LIMIT=2&offset=2&cursor=0000000000000001".to_string(), 2, NextPage::Offset(4)),
+            "/people?order_by=name&limit=2&offset=4"
+        );
+        assert_eq!(
+            next_page_uri("/people?offset=2&limit=2".to_string(), 2, NextPage::Cursor(9)),
+            "/people?limit=2&cursor=0000000000000009"
+        );
+    }
+}
@@ -1,18 +1,27 @@
 use std::collections::HashMap;
-use std::fmt::Display;
 
 use hyper::body::to_bytes;
 use hyper::{Request, Body, Method};
+use hyper::header::HeaderMap;
 
-use sqlite3::{Cursor, Connection};
+use sqlite3::{Connection, Statement, State};
 use sqlite3::Result as SqlResult;
 use sqlite3::Value as SqlValue;
 use sqlite3::Error as SqlError;
 
-use super::super::api_http_server::routing::split_uri_args;
+use super::super::api_http_server::routing::{split_uri_args, parse_uri_args, PathParams};
 use super::table_schema::SqlTableSchema;
+use super::filter::{Filter, FilterOperator, where_clause, merge_repeated, id_list};
+use super::error::{QueryErr, ErrorCode, SQLITE_CONSTRAINT};
+use super::pagination::decode_cursor;
+use super::response::ResponseFormat;
+use super::expand::{Expansion, parse_expand, find_expansion, hidden_fields, expand_rows};
+use super::value::json_to_sql_value;
 
-use json::parse;
+use json::{parse, JsonValue};
+
+// query string args that aren't filters, whichever method uses them
+const RESERVED_ARGS: [&str; 9] = ["format", "confirm", "on_conflict", "limit", "offset", "cursor", "order_by", "fields", "expand"];
 
 #[derive(PartialEq)]
 pub enum HttpMethod {
@@ -20,21 +29,10 @@ pub enum HttpMethod {
     POST,
     DELETE,
     PATCH,
+    PUT,
     INVALID
 }
 
-#[derive(Debug)]
-pub struct QueryErr (
-    pub String,  // description
-    pub bool,  // server fault?
-);
-
-impl Display for QueryErr {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
-    }
-}
-
 // Used to convert the incoming HTTP request to a SQL statement
 #[async_trait::async_trait]
 pub trait Query<'a, T, A> {
@@ -45,15 +43,105 @@ pub trait Query<'a, T, A> {
 
 
 
+// a column to sort GET results by
+#[derive(Clone, Debug)]
+pub struct Ordering {
+    pub field: String,
+    pub descending: bool,
+}
+
+// what a PATCH or DELETE returns, from the request's Prefer header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReturnPreference {
+    // the number of affected rows
+    Count,
+    // an empty body, from 'return=minimal'
+    Minimal,
+    // the affected rows, from 'return=representation'
+    Representation,
+}
+
+impl ReturnPreference {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let preferences = headers.get_all("Prefer")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+
+        for preference in preferences {
+            match preference.trim().to_ascii_lowercase().as_str() {
+                "return=minimal" => return Self::Minimal,
+                "return=representation" => return Self::Representation,
+                _ => ()
+            }
+        }
+        Self::Count
+    }
+
+    // the value of the Preference-Applied header, if a preference was applied
+    pub fn applied(&self) -> Option<&'static str> {
+        match self {
+            Self::Count => None,
+            Self::Minimal => Some("return=minimal"),
+            Self::Representation => Some("return=representation"),
+        }
+    }
+}
+
+// rows returned by a query, and the name of each column in the rows
+pub struct Sqlite3Rows<'a> {
+    pub statement: Statement<'a>,
+    pub columns: Vec<String>,
+}
+
+impl<'a> Sqlite3Rows<'a> {
+    // the values of the next row, or None once every row is read
+    // the type of each value is read from its row, as a sqlite3 Cursor keeps the types of the first row
+    pub fn next_row(&mut self) -> SqlResult<Option<Vec<SqlValue>>> {
+        if self.statement.next()? == State::Done {
+            return Ok(None)
+        }
+
+        let mut row = Vec::with_capacity(self.columns.len());
+        for i in 0..self.columns.len() {
+            row.push(self.statement.read::<SqlValue>(i)?);
+        }
+        Ok(Some(row))
+    }
+}
+
 pub struct Sqlite3Query<'a> {
     pub method: HttpMethod,
     pub table_schema: &'a SqlTableSchema,
-    pub fields_data: HashMap<String, String>,
-    pub filter: HashMap<String, String>,
+    // column name: value of the column's type
+    pub fields_data: HashMap<String, SqlValue>,
+    // rows inserted by a POST or PUT, in the order they were sent
+    pub rows: Vec<HashMap<String, SqlValue>>,
+    // column identifying an existing row to update instead of inserting, id or a unique column
+    pub upsert_key: Option<String>,
+    // conditions of the rows read or changed
+    // for POST and PUT, the parent of a nested route, whose records are the only ones an upsert updates
+    pub filter: Vec<Filter>,
+    // the request targets one record by id, from a route such as /people/{id}
+    pub single_record: bool,
+
+    // pagination of GET requests
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    // only return rows after this id, from a cursor
+    pub after_id: Option<i64>,
+    pub order_by: Vec<Ordering>,
+    // columns returned by a GET, all columns if empty
+    pub fields: Vec<String>,
+    // relations embedded in each row returned by a GET
+    pub expand: Vec<Expansion<'a>>,
+    // format of the response, or the default if None
+    pub format: Option<ResponseFormat>,
+    pub return_preference: ReturnPreference,
 }
 
 #[async_trait::async_trait]
-impl<'a> Query<'a, &'a Connection, SqlResult<Cursor<'a>>> for Sqlite3Query<'a> {
+impl<'a> Query<'a, &'a Connection, SqlResult<Sqlite3Rows<'a>>> for Sqlite3Query<'a> {
     
     async fn from_request(request: &mut Request<Body>, table: &'a SqlTableSchema) -> Result<Self, QueryErr> {
         let method = match request.method().clone() {
@@ -61,54 +149,182 @@ impl<'a> Query<'a, &'a Connection, SqlResult<Cursor<'a>>> for Sqlite3Query<'a> {
             Method::PATCH => HttpMethod::PATCH,
             Method::DELETE => HttpMethod::DELETE,
             Method::POST => HttpMethod::POST,
+            Method::PUT => HttpMethod::PUT,
             _ => HttpMethod::INVALID,
         };
 
-        if method == HttpMethod::INVALID {
-            return Err(QueryErr("Invalid Method".to_string(), false))
+        // only parameters that are fields of the table are used as filters
+        let mut path_filters = Vec::new();
+        if let Some(PathParams(params)) = request.extensions().get::<PathParams>() {
+            for (param, value) in params.iter().filter(|(param, _)| table.field_exists(param)) {
+                path_filters.push(Filter::parse(param, &JsonValue::from(value.as_str()), table)?);
+            }
         }
+        let single_record = path_filters.iter().any(|f| f.field == "id");
 
-        if method == HttpMethod::GET || method == HttpMethod::DELETE {
-            // GET and DELETE are constructed from uri args
+        if method == HttpMethod::INVALID || (single_record && method == HttpMethod::POST) {
+            return Err(
+                QueryErr::new(ErrorCode::MethodNotAllowed, format!("Method not allowed: {}", request.method()))
+                    .with_allow(allowed_methods(single_record))
+            )
+        }
 
-            let (_, uri_args) = split_uri_args(request.uri().to_string());
+        let return_preference = ReturnPreference::from_headers(request.headers());
+
+        let (_, uri_args) = split_uri_args(request.uri().to_string());
+
+        let mut uri_args_parsed: Vec<Filter> = Vec::new();
+        let mut limit = None;
+        let mut offset = None;
+        let mut after_id = None;
+        let mut order_by = Vec::new();
+        let mut fields_value = None;
+        let mut expand_value = None;
+        let mut format = None;
+        let mut upsert_key = None;
+        let mut confirmed = false;
+
+        // keys are lowercase, values keep their case
+        for (left, right) in parse_uri_args(&uri_args) {
+            let right = right.as_str();
+
+            if left == "format" {
+                format = ResponseFormat::from_name(right);
+                if format.is_none() {
+                    return Err(QueryErr::new(ErrorCode::InvalidParameter, format!("Invalid format: {}", right)))
+                }
+                continue
+            }
 
-            let uri_args = uri_args.to_ascii_lowercase();
+            if (method == HttpMethod::DELETE || method == HttpMethod::PATCH) && left == "confirm" {
+                if !right.eq_ignore_ascii_case("true") {
+                    return Err(QueryErr::new(ErrorCode::InvalidParameter, format!("'confirm' must be true: {}", right)))
+                }
+                confirmed = true;
+                continue
+            }
 
-            let mut uri_args_parsed: HashMap<String, String> = HashMap::new();
-            for arg in uri_args.split('&') {
-                let res = arg.split_once('=');
+            if method == HttpMethod::POST && left == "on_conflict" {
+                let field = right.to_lowercase();
+                // prevent sql injection by only allowing valid field names
+                if field != "id" && !table.unique.contains(&field) {
+                    return Err(QueryErr::new(ErrorCode::InvalidParameter, format!("Can't upsert on a field that isn't unique: {}", field)).with_field(&field))
+                }
+                upsert_key = Some(field);
+                continue
+            }
 
-                if res.is_none() {
-                    continue;
+            if method == HttpMethod::GET {
+                match left.as_str() {
+                    "limit" => {
+                        limit = Some(parse_page_arg(&left, right)?);
+                        continue
+                    },
+                    "offset" => {
+                        offset = Some(parse_page_arg(&left, right)?);
+                        continue
+                    },
+                    "cursor" => {
+                        let cursor = decode_cursor(right);
+                        if cursor.is_none() {
+                            return Err(QueryErr::new(ErrorCode::InvalidParameter, format!("Invalid cursor: {}", right)))
+                        }
+                        after_id = cursor;
+                        continue
+                    },
+                    "order_by" => {
+                        order_by = parse_order_by(&right.to_lowercase(), table)?;
+                        continue
+                    },
+                    // fields can be of expanded relations, so are parsed once every relation is known
+                    "fields" => {
+                        fields_value = Some(right.to_lowercase());
+                        continue
+                    },
+                    "expand" => {
+                        expand_value = Some(right.to_lowercase());
+                        continue
+                    },
+                    _ => ()
                 }
+            }
 
-                let (left, right) = res.unwrap();
-                let left = left.to_lowercase();
+            // args used by other methods aren't filters
+            if RESERVED_ARGS.contains(&left.as_str()) {
+                continue
+            }
 
-                let right_with_space = right.replace('+', " ");
+            // GET, DELETE and PUT filters are constructed from uri args
+            if method == HttpMethod::GET || method == HttpMethod::DELETE || method == HttpMethod::PUT {
+                uri_args_parsed.push(Filter::parse(&left, &JsonValue::from(right), table)?);
+            }
+        }
+        let mut uri_args_parsed = merge_repeated(uri_args_parsed);
 
-                if table.field_exists(&left) {
-                    uri_args_parsed.insert(left, right_with_space.to_string());
-                }
+        let mut expand = match expand_value {
+            Some(value) => parse_expand(&value, table)?,
+            None => Vec::new()
+        };
+        let fields = match fields_value {
+            Some(value) => parse_fields(&value, table, &mut expand)?,
+            None => Vec::new()
+        };
+
+        if method == HttpMethod::GET || method == HttpMethod::DELETE {
+            uri_args_parsed.extend(path_filters);
+
+            // requested limit, or the table's default, can't be larger than the maximum page size
+            let mut limit = limit.or(table.page_size);
+            if let Some(max_page_size) = table.max_page_size {
+                limit = Some(limit.unwrap_or(max_page_size).min(max_page_size));
+            }
+
+            // only GET requests are paginated
+            if single_record || method != HttpMethod::GET {
+                limit = None;
+                offset = None;
+                after_id = None;
+            }
+
+            // cursors are only valid when ordered by id
+            if after_id.is_some() && !order_by.is_empty() {
+                return Err(QueryErr::new(ErrorCode::InvalidParameter, "'cursor' can't be used with 'order_by'".to_string()))
+            }
+
+            if method == HttpMethod::DELETE && uri_args_parsed.is_empty() && !table.allow_bulk_delete && !confirmed {
+                return Err(QueryErr::new(
+                    ErrorCode::BulkOperation,
+                    "DELETE without filters would delete every record, add 'confirm=true' to the query string".to_string()
+                ))
             }
 
             return Ok(Self {
                 method,
-                table_schema: &table,
+                table_schema: table,
                 fields_data: HashMap::new(),
+                rows: Vec::new(),
+                upsert_key: None,
                 filter: uri_args_parsed,
+                single_record,
+                limit,
+                offset,
+                after_id,
+                order_by,
+                fields,
+                expand,
+                format,
+                return_preference,
             })
         }
 
         // TODO: possible vunerability in to_bytes
         let body_read_result = to_bytes(request.body_mut()).await;
         if body_read_result.is_err() {
-            return Err(QueryErr("Error reading request body".to_string(), true))
+            return Err(QueryErr::new(ErrorCode::InternalError, "Error reading request body".to_string()))
         }
         let body = String::from_utf8(body_read_result.unwrap().into_iter().collect());
         if body.is_err() {
-            return Err(QueryErr("Error creating string from request body bytes".to_string(), true))
+            return Err(QueryErr::new(ErrorCode::InvalidBody, "Request body isn't valid UTF-8".to_string()))
         }
 
         let body = body.unwrap();
@@ -117,67 +333,118 @@ impl<'a> Query<'a, &'a Connection, SqlResult<Cursor<'a>>> for Sqlite3Query<'a> {
         );
         if parsed.is_err() {
             let error = parsed.err().unwrap();
-            return Err(QueryErr(format!("Error parsing json ( {} ): {}", body, error), false))
+            return Err(QueryErr::new(ErrorCode::InvalidJson, format!("Error parsing json: {}", error)))
         }
         
         let mut content = parsed.unwrap();
-        let columns = content.remove("columns");
-
-        if columns.is_null() {
-            return Err(QueryErr("Error getting 'columns' from json".to_string(), false));
-        }
-        
-        let mut data_hashmap = HashMap::new();
-        if columns.is_object() {
-            for col in columns.entries() {
-                let col_as_str = col.1.as_str();
 
-                if col_as_str.is_none() {
-                    return Err(QueryErr("Columns json contains non-string".to_string(), false))
-                }
+        // PUT replaces the record with the id in the path or query string, or creates it
+        if method == HttpMethod::PUT {
+            uri_args_parsed.extend(path_filters.iter().cloned());
+            let id = uri_args_parsed.iter()
+                .find(|f| f.field == "id" && f.operator == FilterOperator::Eq)
+                .map(|f| f.values[0].clone());
+            if id.is_none() {
+                return Err(QueryErr::new(ErrorCode::InvalidParameter, "PUT requires the id of a record".to_string()))
+            }
+            let id = id.unwrap();
 
-                // prevent sql injection by only allowing valid field names
-                if table.field_exists(&col.0.to_lowercase()) {
-                    data_hashmap.insert(col.0.to_string(), col_as_str.unwrap().to_string());
-                }
+            let mut row = parse_columns(&content.remove("columns"), table)?;
+            if row.get("id").is_some_and(|body_id| *body_id != id) {
+                return Err(QueryErr::new(ErrorCode::InvalidValue, "'id' in json doesn't match the record's id".to_string()).with_field("id"))
             }
-        } else if !columns.is_null() {
-            // null means keep empty columns hashmap, if not null, it is wrong type
-            return Err(QueryErr("'columns' in json is wrong type".to_string(), false))
+            row.insert("id".to_string(), id);
+            scope_row(&mut row, &path_filters)?;
+            complete_row(&mut row, table, None)?;
+
+            // a record of another parent isn't replaced
+            let scope = path_filters.into_iter().filter(|f| f.field != "id").collect();
+
+            return Ok(Self {
+                method,
+                table_schema: table,
+                fields_data: HashMap::new(),
+                rows: vec![row],
+                upsert_key: Some("id".to_string()),
+                filter: scope,
+                single_record: true,
+                limit: None,
+                offset: None,
+                after_id: None,
+                order_by: Vec::new(),
+                fields: Vec::new(),
+                expand: Vec::new(),
+                format,
+                return_preference,
+            })
         }
 
+        if method == HttpMethod::POST {
+            return Ok(Self {
+                method,
+                table_schema: table,
+                fields_data: HashMap::new(),
+                rows: parse_rows(content, table, upsert_key.as_deref(), &path_filters)?,
+                upsert_key,
+                filter: path_filters,
+                single_record,
+                limit: None,
+                offset: None,
+                after_id: None,
+                order_by: Vec::new(),
+                fields: Vec::new(),
+                expand: Vec::new(),
+                format,
+                return_preference,
+            })
+        }
+
+        let mut data_hashmap = parse_columns(&content.remove("columns"), table)?;
+        scope_row(&mut data_hashmap, &path_filters)?;
+
         let filters = content.remove("filters");
-        let mut filters_hashmap = HashMap::new();
+        let mut filters_vec = Vec::new();
 
         if filters.is_object() {
             for filter in filters.entries() {
-                let filter_val = filter.1.as_str();
-                if filter_val.is_none() {
-                    return Err(QueryErr("Filters json contains non-string".to_string(), false))
-                }
-
-                // prevent sql injection by only allowing valid field names
-                if table.field_exists(filter.0) {
-                    filters_hashmap.insert(filter.0.to_string(), filter_val.unwrap().to_string());
-                }
+                filters_vec.push(Filter::parse(&filter.0.to_lowercase(), filter.1, table)?);
             }
         } else if !filters.is_null() {
             // null means keep empty filters hashmap, if not null, it is wrong type
-            return Err(QueryErr("'filters' in json is wrong type".to_string(), false))
+            return Err(QueryErr::new(ErrorCode::InvalidBody, "'filters' in json is wrong type".to_string()))
+        }
+        filters_vec.extend(path_filters);
+
+        if filters_vec.is_empty() && !table.allow_bulk_update && !confirmed {
+            return Err(QueryErr::new(
+                ErrorCode::BulkOperation,
+                "PATCH without filters would update every record, add 'confirm=true' to the query string".to_string()
+            ))
         }
 
         Ok(Self {
             method,
-            table_schema: &table,
+            table_schema: table,
             fields_data: data_hashmap,
-            filter: filters_hashmap
+            rows: Vec::new(),
+            upsert_key: None,
+            filter: filters_vec,
+            single_record,
+            limit: None,
+            offset: None,
+            after_id: None,
+            order_by: Vec::new(),
+            fields: Vec::new(),
+            expand: Vec::new(),
+            format,
+            return_preference,
         })
     }
 
-    fn execute_sql(&'a self, connection: &'a Connection) -> SqlResult<Cursor<'a>> {
+    fn execute_sql(&'a self, connection: &'a Connection) -> SqlResult<Sqlite3Rows<'a>> {
         match self.method {
             HttpMethod::GET => self.construct_get_sql(connection),
-            HttpMethod::POST => self.construct_post_sql(connection),
+            HttpMethod::POST | HttpMethod::PUT => self.construct_post_sql(connection),
             HttpMethod::DELETE => self.construct_delete_sql(connection),
             HttpMethod::PATCH => self.construct_patch_sql(connection),
             _  => SqlResult::Err(
@@ -191,184 +458,741 @@ impl<'a> Query<'a, &'a Connection, SqlResult<Cursor<'a>>> for Sqlite3Query<'a> {
 
 
 impl<'a> Sqlite3Query<'a> {
-    fn construct_get_sql(&'a self, connection: &'a Connection) -> SqlResult<Cursor> {
-        let mut bindings: Vec<SqlValue> = Vec::new();
-        let mut select_builder = "SELECT *".to_string();
-
-        select_builder.push_str(&format!(" FROM {}", self.table_schema.name));
-
-        if self.filter.len() > 0 {
-            select_builder.push_str(" WHERE ");
-
-            for filter in &self.filter {
-                // fields MUST be checked to be valid for the table when constructing query object
-                // or vulnerable to SQL injection
-                select_builder.push_str( &format!("{}=? AND ", filter.0) );
-
-                bindings.push(SqlValue::String(filter.1.clone()));
-            }
-
-            select_builder.remove(select_builder.len()-1);
-            select_builder.remove(select_builder.len()-1);
-            select_builder.remove(select_builder.len()-1);
-            select_builder.remove(select_builder.len()-1);
-            select_builder.remove(select_builder.len()-1);
+    // converts an error executing the query to an error for the client
+    pub fn query_error(&self, error: SqlError) -> QueryErr {
+        let error = QueryErr::from(error);
+        if error.code != ErrorCode::ReferencedRecord || self.method == HttpMethod::DELETE {
+            return error
         }
 
-        let statement = connection.prepare(select_builder);
-        
-        if statement.is_err() {
-            let error = statement.err().unwrap();
-            return Err(error)
+        // a foreign key error when writing is from a value that isn't the id of a record in the referenced table
+        // sqlite doesn't give the field, but it is known if only one written field references a table
+        let written: Vec<&String> = self.table_schema.references()
+            .map(|(field, _)| field)
+            .filter(|field| self.fields_data.contains_key(*field) || self.rows.iter().any(|row| row.contains_key(*field)))
+            .collect();
+
+        // the message keeps the index of the row in a batch
+        let message = error.message.replace("Record is referenced by other records", "Referenced record doesn't exist");
+        let error = QueryErr::new(ErrorCode::InvalidReference, message);
+        match written.as_slice() {
+            [field] => error.with_field(field),
+            _ => error
         }
+    }
 
-        let mut bound = statement.unwrap().cursor();
-        let _res = bound.bind(bindings.as_slice());
+    // columns selected for pagination and expanding relations that aren't requested fields
+    // they are the last columns of each row, with id first, and are removed from the response
+    pub fn hidden_fields(&self) -> Vec<String> {
+        hidden_fields(&self.fields, &self.expand, None)
+    }
 
-        Ok(bound)
+    // embeds related records in each row, and removes hidden columns
+    pub fn expand_rows(&self, connection: &Connection, rows: &mut [Vec<SqlValue>], columns: &mut Vec<String>, format: ResponseFormat) -> SqlResult<()> {
+        expand_rows(connection, &self.expand, rows, columns, self.hidden_fields().len(), format)
     }
 
-    fn construct_post_sql(&'a self, connection: &'a Connection) -> SqlResult<Cursor> {
-        let mut insert_builder = "INSERT INTO ".to_string();
-        insert_builder.push_str(&self.table_schema.name.clone());
-        // null for pk autoincrement col
-        insert_builder.push_str(" VALUES (Null, ");
+    // index of the id column in each row
+    pub fn id_index(&self) -> usize {
+        if self.fields.is_empty() {
+            return 0
+        }
+        self.fields.iter().position(|f| f == "id").unwrap_or(self.fields.len())
+    }
 
+    fn construct_get_sql(&'a self, connection: &'a Connection) -> SqlResult<Sqlite3Rows<'a>> {
         let mut bindings: Vec<SqlValue> = Vec::new();
+        let mut select_builder = match self.fields.is_empty() {
+            true => "SELECT *".to_string(),
+            // fields MUST be checked to be valid for the table when constructing query object
+            // or vulnerable to SQL injection
+            false => format!("SELECT {}", self.fields.join(", ")),
+        };
 
-        if self.fields_data.len() == 0 {
-            return Err(SqlError {message: Some("No parsed data in POST body".to_string()), code: None})
+        // id is always selected, for pagination, and the fields used to find related records
+        for column in self.hidden_fields() {
+            select_builder.push_str(&format!(", {}", column));
         }
-        
-        // iterate over every field and find corresponding value to insert
-        // TODO: test that fields are in correct order consistently
-        for field in &self.table_schema.fields {
-            let field_value = self.fields_data.get(field.0);
-            if field_value.is_none() {
-                return Err(SqlError {message: Some(format!("Missing field value {}", field.0)), code: None})
+
+        select_builder.push_str(&format!(" FROM {}", self.table_schema.name));
+
+        let mut filters = self.filter.clone();
+        if let Some(after_id) = self.after_id {
+            filters.push(Filter::new("id", FilterOperator::Gt, vec![SqlValue::Integer(after_id)]));
+        }
+        select_builder.push_str(&where_clause(&filters, &mut bindings));
+
+        if !self.order_by.is_empty() {
+            // fields MUST be checked to be valid for the table when constructing query object
+            // or vulnerable to SQL injection
+            let mut columns: Vec<String> = self.order_by.iter()
+                .map(|o| format!("{} {}", o.field, if o.descending {"DESC"} else {"ASC"}))
+                .collect();
+
+            // id breaks ties so pages are in a consistent order
+            if !self.order_by.iter().any(|o| o.field == "id") {
+                columns.push("id ASC".to_string());
             }
-            let v = field_value.unwrap();
-            insert_builder.push_str("?,");
-            bindings.push(SqlValue::String(v.clone()))
+            select_builder.push_str(&format!(" ORDER BY {}", columns.join(", ")));
         }
 
-        insert_builder.remove(insert_builder.len()-1);
+        // pages must be in a consistent order
+        let paginated = self.limit.is_some() || self.offset.is_some() || self.after_id.is_some();
+        if paginated {
+            if self.order_by.is_empty() {
+                select_builder.push_str(" ORDER BY id");
+            }
+            // negative limit means no limit
+            select_builder.push_str(" LIMIT ?");
+            bindings.push(SqlValue::Integer(self.limit.map_or(-1, |l| l as i64)));
+        }
+        if let Some(offset) = self.offset {
+            select_builder.push_str(" OFFSET ?");
+            bindings.push(SqlValue::Integer(offset as i64));
+        }
 
-        insert_builder.push_str(")");
+        prepare_rows(connection, select_builder, &bindings)
+    }
 
-        // execute the INSERT statement
-        {
-            let post_statement = connection.prepare(insert_builder);
+    fn construct_post_sql(&'a self, connection: &'a Connection) -> SqlResult<Sqlite3Rows<'a>> {
+        if self.rows.is_empty() {
+            return Err(SqlError {message: Some("No parsed data in POST body".to_string()), code: None})
+        }
+
+        // all rows are inserted, or none are
+        connection.execute("BEGIN")?;
+        let inserted = self.insert_rows(connection)
+            .and_then(|ids| connection.execute("COMMIT").map(|_| ids));
 
-            if post_statement.is_err() {
-                let error = post_statement.err().unwrap();
+        let ids = match inserted {
+            Ok(ids) => ids,
+            Err(error) => {
+                if let Err(rollback_error) = connection.execute("ROLLBACK") {
+                    log::warn!("Error rolling back insert: {:?}", rollback_error.message);
+                }
                 return Err(error)
             }
-            
-            let mut bound = post_statement.unwrap().cursor();
-            let _res = bound.bind(bindings.as_slice());
+        };
 
-            let success = bound.next();
-            if success.is_err() {
-                return Err(success.err().unwrap())
-            }
+        // return a cursor for the new values
+        prepare_rows(
+            connection,
+            format!("SELECT * FROM {} WHERE id IN ({}) ORDER BY id", self.table_schema.name, id_list(&ids)),
+            &[]
+        )
+    }
+
+    // inserts every row, returning the id of each new or updated row
+    // the connection must not be used by another request until the rows are selected
+    fn insert_rows(&self, connection: &Connection) -> SqlResult<Vec<i64>> {
+        // columns are named, so values are inserted correctly whatever order the table's columns are in
+        // id is omitted unless it's the upsert key, so is autoincremented
+        let mut columns: Vec<&str> = self.table_schema.fields.iter().map(|(field, _)| field.as_str()).collect();
+        if self.upsert_key.as_deref() == Some("id") {
+            columns.insert(0, "id");
         }
 
-        // return a cursor for the new values
-        let select_statement = connection.prepare(
-            format!("SELECT * FROM {} ORDER BY id DESC LIMIT 1", self.table_schema.name)
+        let mut insert_builder = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            self.table_schema.name,
+            columns.join(", "),
+            vec!["?"; columns.len()].join(", ")
         );
 
-        if select_statement.is_err() {
-            let error = select_statement.err().unwrap();
-            return Err(error)
+        // an existing row with the same key has every other column replaced
+        // upsert keys MUST be checked to be id or a unique field when constructing query object
+        // or vulnerable to SQL injection
+        let mut scope_bindings = Vec::new();
+        if let Some(upsert_key) = &self.upsert_key {
+            let mut updates: Vec<String> = columns.iter()
+                .filter(|column| *column != upsert_key)
+                .map(|column| format!("{}=excluded.{}", column, column))
+                .collect();
+            // a table with only the key column has nothing else to update
+            if updates.is_empty() {
+                updates.push(format!("{}=excluded.{}", upsert_key, upsert_key));
+            }
+            insert_builder.push_str(&format!(" ON CONFLICT({}) DO UPDATE SET {}", upsert_key, updates.join(", ")));
+            // columns in the condition are of the existing row, so a record of another parent isn't updated
+            insert_builder.push_str(&where_clause(&self.filter, &mut scope_bindings));
         }
 
-        Ok(select_statement.unwrap().cursor())
-    }
-    
-    fn construct_delete_sql(&'a self, connection: &'a Connection) -> SqlResult<Cursor> {
-        let mut bindings: Vec<SqlValue> = Vec::new();
-        let mut delete_builder = format!("DELETE FROM {}", self.table_schema.name);
-
-        if self.filter.len() > 0 {
-            delete_builder.push_str(" WHERE ");
+        // the id of an updated row isn't the last insert rowid, so is returned by the statement
+        insert_builder.push_str(" RETURNING id");
 
-            for filter in &self.filter {
-                // fields MUST be checked to be valid for the table when constructing query object
-                // or vulnerable to SQL injection
-                delete_builder.push_str( &format!("{}=? AND ", filter.0) );
+        // the statement is prepared once and reused for every row
+        let mut insert = connection.prepare(insert_builder)?.cursor();
 
-                bindings.push(SqlValue::String(filter.1.clone()));
+        let mut ids = Vec::new();
+        for (index, row) in self.rows.iter().enumerate() {
+            // iterate over every field and find corresponding value to insert
+            let mut bindings: Vec<SqlValue> = Vec::new();
+            for field in &columns {
+                match row.get(*field) {
+                    Some(value) => bindings.push(value.clone()),
+                    None => return Err(SqlError {message: Some(format!("Missing field value {}", field)), code: None})
+                }
             }
 
-            // remove last AND
-            delete_builder.remove(delete_builder.len()-1);
-            delete_builder.remove(delete_builder.len()-1);
-            delete_builder.remove(delete_builder.len()-1);
-            delete_builder.remove(delete_builder.len()-1);
-            delete_builder.remove(delete_builder.len()-1);
-        }
-        let statement = connection.prepare(delete_builder);
-        
-        if statement.is_err() {
-            let error = statement.err().unwrap();
-            return Err(error)
+            bindings.extend(scope_bindings.iter().cloned());
+
+            // errors in a batch say which row caused them
+            let in_row = |mut error: SqlError| {
+                if self.rows.len() > 1 {
+                    error.message = error.message.map(|message| format!("Row {}: {}", index, message));
+                }
+                error
+            };
+
+            insert.bind(&bindings).map_err(in_row)?;
+            match insert.next().map_err(in_row)? {
+                Some([SqlValue::Integer(id)]) => ids.push(*id),
+                // nothing is returned if the existing row isn't updated
+                _ => {
+                    let message = format!(
+                        "Conflicts with a record that isn't in the route: {}.{}",
+                        self.table_schema.name, self.upsert_key.as_deref().unwrap_or("id")
+                    );
+                    return Err(in_row(SqlError {code: Some(SQLITE_CONSTRAINT), message: Some(message)}))
+                }
+            }
         }
 
-        let mut bound = statement.unwrap().cursor();
-        let _res = bound.bind(bindings.as_slice());
+        Ok(ids)
+    }
+    
+    fn construct_delete_sql(&'a self, connection: &'a Connection) -> SqlResult<Sqlite3Rows<'a>> {
+        let mut bindings: Vec<SqlValue> = Vec::new();
+        let mut delete_builder = format!("DELETE FROM {}", self.table_schema.name);
+        delete_builder.push_str(&where_clause(&self.filter, &mut bindings));
+        if self.return_preference == ReturnPreference::Representation {
+            delete_builder.push_str(" RETURNING *");
+        }
 
-        Ok(bound)
+        prepare_rows(connection, delete_builder, &bindings)
     }
 
-    fn construct_patch_sql(&'a self, connection: &'a Connection) -> SqlResult<Cursor> {
+    fn construct_patch_sql(&'a self, connection: &'a Connection) -> SqlResult<Sqlite3Rows<'a>> {
         let mut patch_builder = format!("UPDATE {} SET ", self.table_schema.name);
 
         let mut bindings: Vec<SqlValue> = Vec::new();
 
-        if self.fields_data.len() == 0 {
+        if self.fields_data.is_empty() {
             return Err(SqlError {message: Some("No parsed data in PATCH body".to_string()), code: None})
         }
 
         for field in &self.table_schema.fields {
-            let field_value = self.fields_data.get(field.0);
+            let field_value = self.fields_data.get(&field.0);
             if field_value.is_none() {
                 continue
             }
 
             let v = field_value.unwrap();
             patch_builder.push_str(&format!("{}=?,", field.0));
-            bindings.push(SqlValue::String(v.clone()))
+            bindings.push(v.clone())
         }
 
         patch_builder.remove(patch_builder.len()-1);
+        patch_builder.push_str(&where_clause(&self.filter, &mut bindings));
+        if self.return_preference == ReturnPreference::Representation {
+            patch_builder.push_str(" RETURNING *");
+        }
+
+        // execute the update statement
+        prepare_rows(connection, patch_builder, &bindings)
+    }
+}
+
+// prepares a statement, binding values to its parameters
+pub fn prepare_rows<'a>(connection: &'a Connection, sql: String, bindings: &[SqlValue]) -> SqlResult<Sqlite3Rows<'a>> {
+    let mut statement = connection.prepare(sql)?;
+    let columns = statement.column_names()?
+        .iter()
+        .map(|column| column.to_ascii_lowercase())
+        .collect();
+
+    for (i, value) in bindings.iter().enumerate() {
+        statement.bind(i + 1, value)?;
+    }
+
+    Ok(Sqlite3Rows {statement, columns})
+}
+
+// methods that can be used at a route, for the Allow header
+pub fn allowed_methods(single_record: bool) -> &'static str {
+    match single_record {
+        true => "OPTIONS, GET, PUT, DELETE, PATCH",
+        false => "OPTIONS, GET, POST, PUT, DELETE, PATCH",
+    }
+}
+
+// rows to insert from a POST body, in one of the forms
+// {"columns": {...}}, [{"columns": {...}}, ...] or {"rows": [{...}, ...]}
+// values from the route's parameters are added to every row
+fn parse_rows(mut content: JsonValue, table: &SqlTableSchema, upsert_key: Option<&str>, scope: &[Filter]) -> Result<Vec<HashMap<String, SqlValue>>, QueryErr> {
+    let (batch, rows) = match content {
+        JsonValue::Array(items) => (true, items.into_iter().map(|mut item| item.remove("columns")).collect()),
+        _ if content.has_key("rows") => match content.remove("rows") {
+            JsonValue::Array(rows) => (true, rows),
+            _ => return Err(QueryErr::new(ErrorCode::InvalidBody, "'rows' in json is wrong type".to_string()))
+        },
+        _ => (false, vec![content.remove("columns")])
+    };
+
+    if rows.is_empty() {
+        return Err(QueryErr::new(ErrorCode::InvalidBody, "No rows in json".to_string()))
+    }
+    if let Some(max_batch_size) = table.max_batch_size {
+        if rows.len() > max_batch_size {
+            return Err(QueryErr::new(
+                ErrorCode::BatchTooLarge,
+                format!("Too many rows: {}, the maximum is {}", rows.len(), max_batch_size)
+            ))
+        }
+    }
+
+    let mut parsed_rows = Vec::new();
+    for (index, row) in rows.iter().enumerate() {
+        let parsed = parse_columns(row, table)
+            .and_then(|mut data| scope_row(&mut data, scope).map(|_| data))
+            .and_then(|mut data| complete_row(&mut data, table, upsert_key).map(|_| data));
+
+        // errors in a batch say which row is invalid
+        match parsed {
+            Ok(data) => parsed_rows.push(data),
+            Err(mut error) if batch => {
+                error.message = format!("Row {}: {}", index, error.message);
+                return Err(error)
+            },
+            Err(error) => return Err(error)
+        }
+    }
+
+    Ok(parsed_rows)
+}
 
-        if self.filter.len() > 0 {
-            patch_builder.push_str(" WHERE");
+// every field is required when inserting, unless it has a default, and the upsert key if it is id
+// defaults are added to the row, so every row has the same fields
+fn complete_row(data: &mut HashMap<String, SqlValue>, table: &SqlTableSchema, upsert_key: Option<&str>) -> Result<(), QueryErr> {
+    let fields = table.fields.iter().map(|(field, _)| field.as_str()).chain(upsert_key);
 
-            for filter in &self.filter {
-                patch_builder.push_str(&format!(" {}=? AND", filter.0));
-                bindings.push(SqlValue::String(filter.1.clone()));
+    for field in fields {
+        if data.contains_key(field) {
+            continue
+        }
+
+        match table.field_options(field).and_then(|options| options.default.clone()) {
+            Some(default) => { data.insert(field.to_string(), default); },
+            None => return Err(QueryErr::new(ErrorCode::MissingField, format!("Missing field value {}", field)).with_field(field))
+        }
+    }
+    Ok(())
+}
+
+// a written row has the values of the route's parameters, e.g. company_id in /companies/{company_id}/people
+// they are added to the row if missing, and a different value is invalid
+fn scope_row(data: &mut HashMap<String, SqlValue>, scope: &[Filter]) -> Result<(), QueryErr> {
+    for filter in scope.iter().filter(|f| f.operator == FilterOperator::Eq) {
+        let value = &filter.values[0];
+        if data.get(&filter.field).is_some_and(|existing| existing != value) {
+            return Err(QueryErr::new(
+                ErrorCode::InvalidValue,
+                format!("'{}' in json doesn't match the route", filter.field)
+            ).with_field(&filter.field))
+        }
+        data.insert(filter.field.clone(), value.clone());
+    }
+    Ok(())
+}
+
+// values of a 'columns' object, converted to each field's type
+fn parse_columns(columns: &JsonValue, table: &SqlTableSchema) -> Result<HashMap<String, SqlValue>, QueryErr> {
+    if columns.is_null() {
+        return Err(QueryErr::new(ErrorCode::InvalidBody, "Error getting 'columns' from json".to_string()));
+    }
+    if !columns.is_object() {
+        return Err(QueryErr::new(ErrorCode::InvalidBody, "'columns' in json is wrong type".to_string()))
+    }
+
+    let mut data_hashmap = HashMap::new();
+    for col in columns.entries() {
+        let field = col.0.to_lowercase();
+
+        // prevent sql injection by only allowing valid field names
+        if let Some(sql_type) = table.field_type(&field) {
+            let value = json_to_sql_value(&field, sql_type, col.1)?;
+            if value == SqlValue::Null && table.field_options(&field).is_some_and(|options| options.not_null) {
+                return Err(QueryErr::new(ErrorCode::InvalidValue, format!("Field '{}' can't be null", field)).with_field(&field))
             }
+            data_hashmap.insert(field, value);
+        }
+    }
+
+    if data_hashmap.is_empty() {
+        return Err(QueryErr::new(ErrorCode::InvalidBody, "No valid columns in json".to_string()))
+    }
+
+    Ok(data_hashmap)
+}
+
+fn parse_page_arg(name: &str, value: &str) -> Result<usize, QueryErr> {
+    value.parse().map_err(|_| QueryErr::new(ErrorCode::InvalidParameter, format!("'{}' must be a positive integer: {}", name, value)))
+}
+
+// parses a comma separated list of fields, where a field starting with '-' is sorted descending
+fn parse_order_by(value: &str, table: &SqlTableSchema) -> Result<Vec<Ordering>, QueryErr> {
+    let mut order_by = Vec::new();
+
+    for field in value.split(',') {
+        let (field, descending) = match field.strip_prefix('-') {
+            Some(field) => (field, true),
+            None => (field, false)
+        };
 
-            patch_builder.remove(patch_builder.len()-1);
-            patch_builder.remove(patch_builder.len()-1);
-            patch_builder.remove(patch_builder.len()-1);
-            patch_builder.remove(patch_builder.len()-1);
+        // prevent sql injection by only allowing valid field names
+        if !table.field_exists(field) {
+            return Err(QueryErr::new(ErrorCode::InvalidParameter, format!("Can't order by invalid field: {}", field)).with_field(field))
         }
 
-        // execute the update statement
-        let patch_statement = connection.prepare(patch_builder);
+        order_by.push(Ordering {field: field.to_string(), descending});
+    }
+
+    Ok(order_by)
+}
+
+// parses a comma separated list of fields to select
+// fields of expanded relations follow the relation and a '.', e.g. company.name
+fn parse_fields(value: &str, table: &SqlTableSchema, expand: &mut [Expansion]) -> Result<Vec<String>, QueryErr> {
+    let mut fields = Vec::new();
+
+    for field in value.split(',') {
+        if let Some((path, related_field)) = field.rsplit_once('.') {
+            let expansion = find_expansion(expand, path);
+            if expansion.is_none() {
+                return Err(QueryErr::new(ErrorCode::InvalidParameter, format!("Can't select a field of a relation that isn't expanded: {}", field)).with_field(field))
+            }
+            let expansion = expansion.unwrap();
+
+            // prevent sql injection by only allowing valid field names
+            if !expansion.relation.table.field_exists(related_field) {
+                return Err(QueryErr::new(ErrorCode::InvalidParameter, format!("Can't select invalid field: {}", field)).with_field(field))
+            }
 
-        if patch_statement.is_err() {
-            let error = patch_statement.err().unwrap();
-            return Err(error)
+            if !expansion.fields.iter().any(|f| f == related_field) {
+                expansion.fields.push(related_field.to_string());
+            }
+            continue
         }
-        
-        let mut bound = patch_statement.unwrap().cursor();
-        let _res = bound.bind(bindings.as_slice());
 
-        return Ok(bound)
+        // prevent sql injection by only allowing valid field names
+        if !table.field_exists(field) {
+            return Err(QueryErr::new(ErrorCode::InvalidParameter, format!("Can't select invalid field: {}", field)).with_field(field))
+        }
+
+        if !fields.iter().any(|f| f == field) {
+            fields.push(field.to_string());
+        }
+    }
+
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+    use super::super::interfaces::SQLType;
+
+    fn rows(connection: &Connection, sql: &str) -> Vec<Vec<SqlValue>> {
+        let mut selected = prepare_rows(connection, sql.to_string(), &[]).unwrap();
+        let mut rows = Vec::new();
+        while let Some(row) = selected.next_row().unwrap() {
+            rows.push(row);
+        }
+        rows
+    }
+
+    fn people(values: &[&str]) -> Connection {
+        let connection = sqlite3::open(":memory:").unwrap();
+        connection.execute("CREATE TABLE people (id INTEGER PRIMARY KEY, company_id INTEGER);").unwrap();
+        for value in values {
+            connection.execute(format!("INSERT INTO people (company_id) VALUES ({});", value)).unwrap();
+        }
+        connection
+    }
+
+    fn people_table() -> SqlTableSchema {
+        SqlTableSchema::new("people", vec![("company_id".to_string(), SQLType::Integer)])
+    }
+
+    // a request with the parameters of the route it matched
+    fn request(method: Method, uri: &str, params: &[(&str, &str)], body: &str) -> Request<Body> {
+        let mut request = Request::builder().method(method).uri(uri).body(Body::from(body.to_string())).unwrap();
+        let params = params.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        request.extensions_mut().insert(PathParams(params));
+        request
+    }
+
+    // the rows returned by a request, or the error returned to the client
+    async fn send(connection: &Connection, table: &SqlTableSchema, mut request: Request<Body>) -> Result<Vec<Vec<SqlValue>>, QueryErr> {
+        let query = Sqlite3Query::from_request(&mut request, table).await?;
+        let mut selected = query.execute_sql(connection).map_err(|error| query.query_error(error))?;
+        let mut rows = Vec::new();
+        while let Some(row) = selected.next_row().map_err(|error| query.query_error(error))? {
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+
+    #[tokio::test]
+    async fn put_on_a_nested_route_only_replaces_records_of_the_parent() {
+        let connection = people(&["2"]);
+        let table = people_table();
+
+        let moved = request(Method::PUT, "/companies/1/people/1", &[("company_id", "1"), ("id", "1")], r#"{"columns": {"company_id": 1}}"#);
+        let error = send(&connection, &table, moved).await.err().unwrap();
+        assert_eq!(error.code, ErrorCode::ConstraintViolation);
+        assert_eq!(error.field.as_deref(), Some("id"));
+        assert_eq!(rows(&connection, "SELECT id, company_id FROM people"), vec![vec![SqlValue::Integer(1), SqlValue::Integer(2)]]);
+
+        let replaced = request(Method::PUT, "/companies/2/people/1", &[("company_id", "2"), ("id", "1")], r#"{"columns": {"company_id": 2}}"#);
+        assert_eq!(send(&connection, &table, replaced).await.unwrap(), vec![vec![SqlValue::Integer(1), SqlValue::Integer(2)]]);
+    }
+
+    #[tokio::test]
+    async fn upserts_on_a_nested_route_roll_back_for_records_of_another_parent() {
+        let connection = people(&["1", "2"]);
+        let table = people_table();
+
+        let upsert = request(
+            Method::POST,
+            "/companies/1/people?on_conflict=id",
+            &[("company_id", "1")],
+            r#"[{"columns": {"id": 1}}, {"columns": {"id": 2}}, {"columns": {"id": 3}}]"#
+        );
+        let error = send(&connection, &table, upsert).await.err().unwrap();
+        assert_eq!(error.code, ErrorCode::ConstraintViolation);
+        assert!(error.message.starts_with("Row 1: "));
+        assert_eq!(rows(&connection, "SELECT id, company_id FROM people"), vec![
+            vec![SqlValue::Integer(1), SqlValue::Integer(1)],
+            vec![SqlValue::Integer(2), SqlValue::Integer(2)],
+        ]);
+    }
+
+    #[tokio::test]
+    async fn rolls_back_every_row_of_a_batch_that_fails() {
+        let connection = people(&["1"]);
+        connection.execute("CREATE UNIQUE INDEX people_company_id ON people (company_id);").unwrap();
+        let table = people_table();
+
+        let inserted = request(Method::POST, "/people", &[], r#"[{"columns": {"company_id": 2}}, {"columns": {"company_id": 1}}]"#);
+        let error = send(&connection, &table, inserted).await.err().unwrap();
+        assert_eq!(error.code, ErrorCode::ConstraintViolation);
+        assert_eq!(error.field.as_deref(), Some("company_id"));
+        assert_eq!(error.message, "Row 1: UNIQUE constraint failed: people.company_id");
+        assert_eq!(rows(&connection, "SELECT company_id FROM people"), vec![vec![SqlValue::Integer(1)]]);
+
+        // the transaction is finished, so later requests aren't part of it
+        let inserted = request(Method::POST, "/people", &[], r#"{"rows": [{"company_id": 2}, {"company_id": 3}]}"#);
+        assert_eq!(send(&connection, &table, inserted).await.unwrap(), vec![
+            vec![SqlValue::Integer(2), SqlValue::Integer(2)],
+            vec![SqlValue::Integer(3), SqlValue::Integer(3)],
+        ]);
+    }
+
+    #[tokio::test]
+    async fn errors_in_a_batch_start_with_the_row() {
+        let connection = sqlite3::open(":memory:").unwrap();
+        connection.execute("CREATE TABLE people (id INTEGER PRIMARY KEY, company_id INTEGER CHECK (company_id > 0));").unwrap();
+        let table = people_table();
+
+        let missing = request(Method::POST, "/people", &[], r#"[{"columns": {"company_id": 1}}, {"columns": {"name": "a"}}]"#);
+        let error = send(&connection, &table, missing).await.err().unwrap();
+        assert!(error.message.starts_with("Row 1: "));
+
+        let checked = request(Method::POST, "/people", &[], r#"[{"columns": {"company_id": 1}}, {"columns": {"company_id": 0}}]"#);
+        let error = send(&connection, &table, checked).await.err().unwrap();
+        assert_eq!(error.code, ErrorCode::InvalidValue);
+        assert!(error.message.starts_with("Row 1: CHECK constraint failed"));
+
+        // a single row isn't a batch
+        let checked = request(Method::POST, "/people", &[], r#"{"columns": {"company_id": 0}}"#);
+        let error = send(&connection, &table, checked).await.err().unwrap();
+        assert!(error.message.starts_with("CHECK constraint failed"));
+
+        assert!(rows(&connection, "SELECT company_id FROM people").is_empty());
+    }
+
+    #[tokio::test]
+    async fn upserts_update_existing_rows_instead_of_inserting() {
+        let connection = people(&["1"]);
+        let table = people_table();
+
+        let upsert = request(Method::POST, "/people?on_conflict=id", &[], r#"[{"columns": {"id": 1, "company_id": 5}}, {"columns": {"id": 4, "company_id": 6}}]"#);
+        assert_eq!(send(&connection, &table, upsert).await.unwrap(), vec![
+            vec![SqlValue::Integer(1), SqlValue::Integer(5)],
+            vec![SqlValue::Integer(4), SqlValue::Integer(6)],
+        ]);
+
+        let replaced = request(Method::PUT, "/people/1", &[("id", "1")], r#"{"columns": {"company_id": 7}}"#);
+        assert_eq!(send(&connection, &table, replaced).await.unwrap(), vec![vec![SqlValue::Integer(1), SqlValue::Integer(7)]]);
+
+        assert_eq!(rows(&connection, "SELECT id, company_id FROM people ORDER BY id"), vec![
+            vec![SqlValue::Integer(1), SqlValue::Integer(7)],
+            vec![SqlValue::Integer(4), SqlValue::Integer(6)],
+        ]);
+    }
+
+    #[tokio::test]
+    async fn upserts_on_a_unique_field_keep_the_id() {
+        let connection = sqlite3::open(":memory:").unwrap();
+        connection.execute("CREATE TABLE people (id INTEGER PRIMARY KEY, name TEXT UNIQUE, age INTEGER);").unwrap();
+        connection.execute("INSERT INTO people (name, age) VALUES ('a', 1);").unwrap();
+        let mut table = SqlTableSchema::new("people", vec![("name".to_string(), SQLType::Text), ("age".to_string(), SQLType::Integer)]);
+        table.unique.push("name".to_string());
+
+        let upsert = request(Method::POST, "/people?on_conflict=name", &[], r#"[{"columns": {"name": "a", "age": 2}}, {"columns": {"name": "b", "age": 3}}]"#);
+        send(&connection, &table, upsert).await.unwrap();
+
+        assert_eq!(rows(&connection, "SELECT id, name, age FROM people ORDER BY id"), vec![
+            vec![SqlValue::Integer(1), SqlValue::String("a".to_string()), SqlValue::Integer(2)],
+            vec![SqlValue::Integer(2), SqlValue::String("b".to_string()), SqlValue::Integer(3)],
+        ]);
+    }
+
+    #[tokio::test]
+    async fn orders_by_fields_in_either_direction() {
+        let connection = people(&["2", "1", "2"]);
+        let table = people_table();
+
+        let descending = request(Method::GET, "/people?order_by=-company_id", &[], "");
+        assert_eq!(send(&connection, &table, descending).await.unwrap(), vec![
+            vec![SqlValue::Integer(1), SqlValue::Integer(2)],
+            vec![SqlValue::Integer(3), SqlValue::Integer(2)],
+            vec![SqlValue::Integer(2), SqlValue::Integer(1)],
+        ]);
+
+        let ascending = request(Method::GET, "/people?ORDER_BY=Company_Id,-id", &[], "");
+        assert_eq!(send(&connection, &table, ascending).await.unwrap(), vec![
+            vec![SqlValue::Integer(2), SqlValue::Integer(1)],
+            vec![SqlValue::Integer(3), SqlValue::Integer(2)],
+            vec![SqlValue::Integer(1), SqlValue::Integer(2)],
+        ]);
+    }
+
+    #[tokio::test]
+    async fn rejects_ordering_by_unknown_fields() {
+        let connection = people(&["1"]);
+        let table = people_table();
+
+        let ordered = request(Method::GET, "/people?order_by=company_id,-name", &[], "");
+        let error = send(&connection, &table, ordered).await.err().unwrap();
+        assert_eq!(error.code, ErrorCode::InvalidParameter);
+        assert_eq!(error.field.as_deref(), Some("name"));
+    }
+
+    #[tokio::test]
+    async fn rejects_filters_on_unknown_fields() {
+        let connection = people(&["1", "2"]);
+        let mut table = people_table();
+        table.allow_bulk_delete = true;
+        table.allow_bulk_update = true;
+
+        let deleted = request(Method::DELETE, "/people?compnay_id=1", &[], "");
+        let error = send(&connection, &table, deleted).await.err().unwrap();
+        assert_eq!(error.code, ErrorCode::InvalidParameter);
+        assert_eq!(error.field.as_deref(), Some("compnay_id"));
+
+        let updated = request(Method::PATCH, "/people", &[], r#"{"columns": {"company_id": 3}, "filters": {"ID": 1, "nmae": "a"}}"#);
+        let error = send(&connection, &table, updated).await.err().unwrap();
+        assert_eq!(error.field.as_deref(), Some("nmae"));
+
+        assert_eq!(rows(&connection, "SELECT company_id FROM people"), vec![vec![SqlValue::Integer(1)], vec![SqlValue::Integer(2)]]);
+    }
+
+    #[tokio::test]
+    async fn args_of_other_methods_are_not_filters() {
+        let connection = people(&["1", "2"]);
+        let table = people_table();
+
+        let deleted = request(Method::DELETE, "/people?company_id=1&limit=1&fields=id", &[], "");
+        send(&connection, &table, deleted).await.unwrap();
+        assert_eq!(rows(&connection, "SELECT company_id FROM people"), vec![vec![SqlValue::Integer(2)]]);
+    }
+
+    #[tokio::test]
+    async fn refuses_delete_and_patch_without_filters() {
+        let connection = people(&["1", "2"]);
+        let table = people_table();
+
+        let deleted = request(Method::DELETE, "/people", &[], "");
+        assert_eq!(send(&connection, &table, deleted).await.err().unwrap().code, ErrorCode::BulkOperation);
+
+        let updated = request(Method::PATCH, "/people", &[], r#"{"columns": {"company_id": 3}}"#);
+        assert_eq!(send(&connection, &table, updated).await.err().unwrap().code, ErrorCode::BulkOperation);
+
+        // a misspelled filter is rejected, rather than treated as no filter
+        let misspelled = request(Method::DELETE, "/people?compnay_id=1&confirm=true", &[], "");
+        assert_eq!(send(&connection, &table, misspelled).await.err().unwrap().code, ErrorCode::InvalidParameter);
+
+        assert_eq!(rows(&connection, "SELECT company_id FROM people"), vec![vec![SqlValue::Integer(1)], vec![SqlValue::Integer(2)]]);
+    }
+
+    #[tokio::test]
+    async fn confirm_allows_delete_and_patch_without_filters() {
+        let connection = people(&["1", "2"]);
+        let table = people_table();
+
+        let not_confirmed = request(Method::DELETE, "/people?confirm=false", &[], "");
+        let error = send(&connection, &table, not_confirmed).await.err().unwrap();
+        assert_eq!(error.code, ErrorCode::InvalidParameter);
+        assert_eq!(error.code.status(), StatusCode::BAD_REQUEST);
+
+        let updated = request(Method::PATCH, "/people?confirm=true", &[], r#"{"columns": {"company_id": 3}}"#);
+        send(&connection, &table, updated).await.unwrap();
+        assert_eq!(rows(&connection, "SELECT company_id FROM people"), vec![vec![SqlValue::Integer(3)], vec![SqlValue::Integer(3)]]);
+
+        let deleted = request(Method::DELETE, "/people?confirm=true", &[], "");
+        send(&connection, &table, deleted).await.unwrap();
+        assert!(rows(&connection, "SELECT company_id FROM people").is_empty());
+    }
+
+    #[test]
+    fn reads_values_after_a_null_in_the_first_row() {
+        let connection = people(&["NULL", "1", "2"]);
+        let company_ids: Vec<SqlValue> = rows(&connection, "SELECT company_id FROM people ORDER BY id")
+            .into_iter()
+            .map(|mut row| row.remove(0))
+            .collect();
+
+        assert!(matches!(
+            company_ids.as_slice(),
+            [SqlValue::Null, SqlValue::Integer(1), SqlValue::Integer(2)]
+        ));
+    }
+
+    #[test]
+    fn reads_null_after_a_value_in_the_first_row() {
+        let connection = people(&["1", "NULL"]);
+        let company_ids: Vec<SqlValue> = rows(&connection, "SELECT company_id FROM people ORDER BY id")
+            .into_iter()
+            .map(|mut row| row.remove(0))
+            .collect();
+
+        assert!(matches!(company_ids.as_slice(), [SqlValue::Integer(1), SqlValue::Null]));
+    }
+
+    #[test]
+    fn binds_values_and_names_columns() {
+        let connection = people(&["1", "2"]);
+        let mut selected = prepare_rows(
+            &connection,
+            "SELECT ID, company_id FROM people WHERE company_id = ?".to_string(),
+            &[SqlValue::Integer(2)]
+        ).unwrap();
+
+        assert_eq!(selected.columns, vec!["id", "company_id"]);
+        assert!(matches!(selected.next_row().unwrap().as_deref(), Some([SqlValue::Integer(2), SqlValue::Integer(2)])));
+        assert!(selected.next_row().unwrap().is_none());
     }
 }
@@ -2,35 +2,69 @@ use sqlite3::Value as SqlValue;
 
 use json::JsonValue;
 
+use super::table_schema::SqlTableSchema;
+use super::value::sql_to_json_value;
+
+// how each row is represented in a response
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseFormat {
+    // [1, "john", 5]
+    Array,
+    // {"id": 1, "name": "john", "age": 5}
+    Object,
+}
+
+impl ResponseFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "array" => Some(Self::Array),
+            "object" => Some(Self::Object),
+            _ => None
+        }
+    }
+}
+
+// columns contains the name of each value in a row
+// the type of each column is found in the table
 pub trait ResponseBuilder<T> {
-    fn from_row_data(row_data: Vec<Vec<T>>) -> String;
+    fn from_row_data(row_data: Vec<Vec<T>>, columns: &[String], table: &SqlTableSchema, format: ResponseFormat) -> String;
+    fn from_single_row(row: Vec<T>, columns: &[String], table: &SqlTableSchema, format: ResponseFormat) -> String;
 }
 
 pub struct Sqlite3ResponseBuilder;
 impl ResponseBuilder<SqlValue> for Sqlite3ResponseBuilder {
-    fn from_row_data(row_data: Vec<Vec<SqlValue>>) -> String {
+    fn from_row_data(row_data: Vec<Vec<SqlValue>>, columns: &[String], table: &SqlTableSchema, format: ResponseFormat) -> String {
         let mut root = JsonValue::Array(vec![]);
         
         for row in row_data {
-            let mut row_root = JsonValue::Array(vec![]);
-
-            for val in row {
-                let json_value = match val {
-                    SqlValue::Float(f) => JsonValue::Number(json::number::Number::from(f)),
-                    SqlValue::Integer(i) => JsonValue::Number(json::number::Number::from(i)),
-                    SqlValue::String(s) => JsonValue::String(s),
-                    SqlValue::Null => JsonValue::Null,
-                    t => {
-                        log::debug!("Invalid SQLite3 type: {:?}", t);
-                        continue
-                    }
-                };
-                let _ = row_root.push(json_value);
-            }
-
-            let _ = root.push(row_root);
+            let _ = root.push(row_to_json(row, columns, table, format));
         }
 
         root.to_string()
     }
+
+    fn from_single_row(row: Vec<SqlValue>, columns: &[String], table: &SqlTableSchema, format: ResponseFormat) -> String {
+        row_to_json(row, columns, table, format).to_string()
+    }
+}
+
+pub fn row_to_json(row: Vec<SqlValue>, columns: &[String], table: &SqlTableSchema, format: ResponseFormat) -> JsonValue {
+    let mut row_root = match format {
+        ResponseFormat::Array => JsonValue::Array(vec![]),
+        ResponseFormat::Object => JsonValue::new_object(),
+    };
+
+    for (val, column) in row.into_iter().zip(columns) {
+        let json_value = match (table.relation(column), val) {
+            // related records are embedded in the row as json
+            (Some(_), SqlValue::String(related)) => json::parse(&related).unwrap_or(JsonValue::Null),
+            (_, val) => sql_to_json_value(table.field_type(column), val),
+        };
+        match format {
+            ResponseFormat::Array => { let _ = row_root.push(json_value); },
+            ResponseFormat::Object => row_root[column.as_str()] = json_value,
+        }
+    }
+
+    row_root
 }
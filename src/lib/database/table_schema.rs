@@ -1,18 +1,203 @@
 use std::collections::HashMap;
 
+use sqlite3::Value as SqlValue;
+
 use super::interfaces::SQLType;
 
-#[derive(Debug)]
+// number of levels of relations that can be expanded, e.g. expand=employees.company.employees
+pub const MAX_EXPAND_DEPTH: usize = 3;
+
+// options of a field, from an inline table in the config
+// e.g. age = { type = "integer", nullable = false, default = 0, check = "age >= 0" }
+#[derive(Debug, Clone, Default)]
+pub struct FieldOptions {
+    // collation used when comparing the field's values, e.g. NOCASE
+    pub collate: Option<String>,
+    // from 'nullable = false'
+    pub not_null: bool,
+    // value of the field's type used when a row is inserted without the field
+    pub default: Option<SqlValue>,
+    // SQL expression that every value must satisfy
+    pub check: Option<String>,
+    // name of a table whose id the field's values are
+    pub references: Option<String>,
+    // SQL action when the referenced record is deleted, e.g. CASCADE
+    pub on_delete: Option<String>,
+}
+
+// an index of a table, from 'indexes' in the config
+// e.g. { fields = ["name", "age"], unique = true, where = "age >= 18" }
+#[derive(Debug, Clone)]
+pub struct Index {
+    // name in the database, '{table}_{name}_idx', where the name in the config defaults to the fields
+    pub name: String,
+    // columns of the index, in order
+    pub fields: Vec<String>,
+    pub unique: bool,
+    // SQL expression, only rows satisfying it are indexed
+    pub condition: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelationKind {
+    // a field of this table references the other table, e.g. people.company_id
+    ManyToOne,
+    // a field of the other table references this table, e.g. companies to people.company_id
+    OneToMany,
+}
+
+// a table related to another by a field that references a table
+#[derive(Debug, Clone)]
+pub struct Relation {
+    // name used to expand the relation
+    // the referencing field without '_id' for ManyToOne, the referencing table for OneToMany
+    pub name: String,
+    pub kind: RelationKind,
+    // the field that references a table, in this table for ManyToOne, in the other table for OneToMany
+    pub field: String,
+    // the related table, with its own relations until the maximum expand depth
+    pub table: SqlTableSchema,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct SqlTableSchema {
     pub name: String,
 
-    // col name: data type
-    pub fields: HashMap<String, SQLType>
+    // (col name, data type), in the order the columns are declared
+    pub fields: Vec<(String, SQLType)>,
+    // col name: options, for fields declared with an inline table
+    pub field_options: HashMap<String, FieldOptions>,
+
+    // number of rows returned by a GET if no limit is given, None returns all rows
+    pub page_size: Option<usize>,
+    // maximum number of rows returned by a GET
+    pub max_page_size: Option<usize>,
+    // maximum number of rows inserted by a POST, None allows any number
+    pub max_batch_size: Option<usize>,
+
+    // columns with a unique index, which can be used as upsert keys
+    pub unique: Vec<String>,
+    // indexes created when the server starts
+    pub indexes: Vec<Index>,
+
+    // DELETE and PATCH requests without filters can change every row
+    // if false, they must be confirmed with 'confirm=true'
+    pub allow_bulk_delete: bool,
+    pub allow_bulk_update: bool,
+
+    // tables related by references, found once every table is read
+    pub relations: Vec<Relation>,
 }
 
 impl SqlTableSchema {
+    // a table with the fields, and no other options set
+    pub fn new(name: &str, fields: Vec<(String, SQLType)>) -> Self {
+        Self {
+            name: name.to_string(),
+            fields,
+            ..Self::default()
+        }
+    }
+
     pub fn field_exists(&self, field_name: &str) -> bool {
         // id field is always present
-        self.fields.contains_key(field_name) || field_name == "id"
+        self.field_type(field_name).is_some()
+    }
+
+    pub fn field_options(&self, field_name: &str) -> Option<&FieldOptions> {
+        self.field_options.get(field_name)
     }
+
+    // fields that reference another table, and the name of the table
+    pub fn references(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.fields.iter().filter_map(move |(field, _)| {
+            self.field_options(field)
+                .and_then(|options| options.references.as_ref())
+                .map(|table| (field, table))
+        })
+    }
+
+    pub fn relation(&self, name: &str) -> Option<&Relation> {
+        self.relations.iter().find(|relation| relation.name == name)
+    }
+
+    pub fn field_type(&self, field_name: &str) -> Option<&SQLType> {
+        if field_name == "id" {
+            return Some(&SQLType::Integer)
+        }
+        self.fields.iter().find(|(name, _)| name == field_name).map(|(_, sql_type)| sql_type)
+    }
+}
+
+// the tables with their relations, from fields that reference a table
+// each level of related tables has the relations of the level below it, until the maximum expand depth
+pub fn relate_tables(tables: &[SqlTableSchema]) -> Vec<SqlTableSchema> {
+    let tables: Vec<SqlTableSchema> = tables.iter()
+        .map(|table| SqlTableSchema {relations: Vec::new(), ..table.clone()})
+        .collect();
+
+    let mut related = tables.clone();
+    for _ in 0..MAX_EXPAND_DEPTH {
+        related = tables.iter().map(|schema| with_relations(schema, &tables, &related)).collect();
+    }
+    related
+}
+
+// the table with its relations to the related tables
+// tables are without relations, related are the same tables with the relations they can expand
+fn with_relations(schema: &SqlTableSchema, tables: &[SqlTableSchema], related: &[SqlTableSchema]) -> SqlTableSchema {
+    let related_table = |name: &str| related.iter()
+        .find(|table| table.name == name)
+        .cloned()
+        .expect("Related table isn't in the config");
+
+    let mut relations = Vec::new();
+    for (field, table) in schema.references() {
+        relations.push(Relation {
+            name: relation_name(field),
+            kind: RelationKind::ManyToOne,
+            field: field.clone(),
+            table: related_table(table),
+        });
+    }
+    for other in tables {
+        let fields: Vec<&String> = other.references()
+            .filter(|(_, table)| **table == schema.name)
+            .map(|(field, _)| field)
+            .collect();
+        // a table referencing this table with several fields has a relation for each field
+        for field in &fields {
+            let name = match fields.len() {
+                1 => other.name.clone(),
+                _ => format!("{}_{}", other.name, relation_name(field)),
+            };
+            relations.push(Relation {
+                name,
+                kind: RelationKind::OneToMany,
+                field: (*field).clone(),
+                table: related_table(&other.name),
+            });
+        }
+    }
+
+    // related records replace the value of a field with the relation's name, which must be its own field
+    for relation in &relations {
+        let is_own_field = relation.kind == RelationKind::ManyToOne && relation.name == relation.field;
+        if schema.field_exists(&relation.name) && !is_own_field {
+            panic!("Relation '{}' of table '{}' has the same name as a field", relation.name, schema.name)
+        }
+        if relations.iter().filter(|other| other.name == relation.name).count() > 1 {
+            panic!("Table '{}' has several relations named '{}'", schema.name, relation.name)
+        }
+    }
+
+    SqlTableSchema {relations, ..schema.clone()}
+}
+
+// company_id is the company relation, fields without '_id' are named the same as the field
+fn relation_name(field: &str) -> String {
+    field.strip_suffix("_id")
+        .filter(|name| !name.is_empty())
+        .unwrap_or(field)
+        .to_string()
 }
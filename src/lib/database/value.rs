@@ -0,0 +1,128 @@
+use sqlite3::Value as SqlValue;
+use json::JsonValue;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+
+use super::interfaces::SQLType;
+use super::error::{QueryErr, ErrorCode};
+
+// formats values of date and datetime fields are stored in, so they can be compared as text
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const DATETIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+// converts a json value to a value of the field's type
+// strings are parsed, so values from query strings or string json values can be used for any type
+pub fn json_to_sql_value(field: &str, sql_type: &SQLType, value: &JsonValue) -> Result<SqlValue, QueryErr> {
+    if value.is_null() {
+        return Ok(SqlValue::Null)
+    }
+
+    let converted = match sql_type {
+        SQLType::Null => None,
+        SQLType::Integer => match value {
+            JsonValue::Number(_) => value.as_f64()
+                .filter(|f| f.fract() == 0.0)
+                .and_then(|_| value.as_i64())
+                .map(SqlValue::Integer),
+            JsonValue::Boolean(b) => Some(SqlValue::Integer(*b as i64)),
+            _ => value.as_str().and_then(|s| s.trim().parse().ok()).map(SqlValue::Integer),
+        },
+        SQLType::Real => match value {
+            JsonValue::Number(_) => value.as_f64().map(SqlValue::Float),
+            _ => value.as_str().and_then(|s| s.trim().parse().ok()).map(SqlValue::Float),
+        },
+        SQLType::Text => value.as_str().map(|s| SqlValue::String(s.to_string())),
+        // stored as 0 or 1
+        SQLType::Boolean => match value {
+            JsonValue::Boolean(b) => Some(*b),
+            JsonValue::Number(_) => match value.as_i64() {
+                Some(0) => Some(false),
+                Some(1) => Some(true),
+                _ => None
+            },
+            _ => value.as_str().and_then(|s| match s.trim().to_ascii_lowercase().as_str() {
+                "true" | "1" => Some(true),
+                "false" | "0" => Some(false),
+                _ => None
+            }),
+        }.map(|b| SqlValue::Integer(b as i64)),
+        SQLType::Date => value.as_str()
+            .and_then(|s| NaiveDate::parse_from_str(s.trim(), DATE_FORMAT).ok())
+            .map(|date| SqlValue::String(date.format(DATE_FORMAT).to_string())),
+        SQLType::DateTime => value.as_str()
+            .and_then(parse_datetime)
+            .map(SqlValue::String),
+        // base64 in json
+        SQLType::Blob => value.as_str()
+            .and_then(|s| base64::decode(s.trim()).ok())
+            .map(SqlValue::Binary),
+        // any json value, stored as json text
+        SQLType::Json => Some(SqlValue::String(value.dump())),
+    };
+
+    converted.ok_or_else(|| QueryErr::new(
+        ErrorCode::InvalidValue,
+        format!("Invalid value for field '{}': expected {}, found {}", field, type_name(sql_type), value.dump())
+    ).with_field(field))
+}
+
+// converts a value of the field's type to json, the reverse of json_to_sql_value
+// None is an untyped column, e.g. from an expression
+pub fn sql_to_json_value(sql_type: Option<&SQLType>, value: SqlValue) -> JsonValue {
+    match (sql_type, value) {
+        (Some(SQLType::Boolean), SqlValue::Integer(i)) => JsonValue::Boolean(i != 0),
+        // invalid json, e.g. from a row not added by the api, is returned as a string
+        (Some(SQLType::Json), SqlValue::String(s)) => json::parse(&s).unwrap_or(JsonValue::String(s)),
+        (_, SqlValue::Float(f)) => JsonValue::Number(json::number::Number::from(f)),
+        (_, SqlValue::Integer(i)) => JsonValue::Number(json::number::Number::from(i)),
+        (_, SqlValue::String(s)) => JsonValue::String(s),
+        (_, SqlValue::Binary(b)) => JsonValue::String(base64::encode(b)),
+        (_, SqlValue::Null) => JsonValue::Null,
+    }
+}
+
+// a value as an SQL literal, for statements that can't have bound values, such as CREATE TABLE
+pub fn sql_literal(value: &SqlValue) -> String {
+    match value {
+        SqlValue::Null => "NULL".to_string(),
+        SqlValue::Integer(i) => i.to_string(),
+        // debug formatting always has a decimal point or exponent, so stays a real
+        SqlValue::Float(f) => format!("{:?}", f),
+        SqlValue::String(s) => format!("'{}'", s.replace('\'', "''")),
+        SqlValue::Binary(b) => format!("X'{}'", b.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
+    }
+}
+
+// an ISO-8601 datetime, or a date at midnight
+// datetimes with an offset are converted to UTC, datetimes without an offset are kept as they are
+fn parse_datetime(value: &str) -> Option<String> {
+    let value = value.trim();
+
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Some(format!("{}Z", datetime.with_timezone(&Utc).format(DATETIME_FORMAT)))
+    }
+
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(datetime.format(DATETIME_FORMAT).to_string())
+        }
+    }
+
+    NaiveDate::parse_from_str(value, DATE_FORMAT)
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|datetime| datetime.format(DATETIME_FORMAT).to_string())
+}
+
+pub fn type_name(sql_type: &SQLType) -> &'static str {
+    match sql_type {
+        SQLType::Null => "null",
+        SQLType::Integer => "integer",
+        SQLType::Real => "real",
+        SQLType::Text => "text",
+        SQLType::Boolean => "boolean",
+        SQLType::Date => "date",
+        SQLType::DateTime => "datetime",
+        SQLType::Blob => "blob",
+        SQLType::Json => "json",
+    }
+}
@@ -12,7 +12,7 @@ use std::collections::HashMap;
 pub fn enable_logging(config: &HashMap<String, String>) {
     // panic if called more than once
 
-    let _init = match config.get("loglevel") {
+    match config.get("loglevel") {
         Some(val) => {
 
             Builder::new()